@@ -1,3 +1,8 @@
+use binary;
+use disasm::{self, Decoded, Flow, DEFAULT_BITNESS};
+
+use std::cell::OnceCell;
+
 pub trait Addressable {
     fn addr(&self) -> Option<usize>;
 }
@@ -8,9 +13,11 @@ pub struct ForeignInfo {
     pub foreign_addr: usize,
     /// Name of the foreign block
     pub foreign_name: String,
+    /// Whether it stands for a call of a traced function, not foreign code
+    pub stub: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Instr {
     /// Address of the instr
     pub addr: usize,
@@ -20,8 +27,16 @@ pub struct Instr {
     pub text: String,
     /// Is branch
     pub isbr: bool,
+    /// Decoded hexdump, filled on the first use
+    pub decoded: Lazy,
 }
 
+/// Hexdump decoded as the code of default bitness on the first use
+///
+/// `None` if there is no hexdump or it is not valid code.
+#[derive(Debug, Clone, Default)]
+pub struct Lazy(OnceCell<Option<Decoded>>);
+
 #[derive(Debug)]
 pub struct Block {
     pub instrs: Vec<Instr>,
@@ -35,13 +50,75 @@ impl Addressable for ForeignInfo {
 
 impl Addressable for Block {
     fn addr(&self) -> Option<usize> {
-        self.instrs.iter().nth(0).map(|x| x.addr)
+        self.instrs.first().map(|x| x.addr)
+    }
+}
+
+/// Decodes the hexdump of the instr at `addr`, the disasm text is not looked at
+pub fn decode_dump(hex: &str, addr: usize, bitness: u32) -> Option<Decoded> {
+    if hex.is_empty() {
+        return None;
+    }
+    disasm::decode_one(&binary::decode_hex(hex)?, addr, bitness)
+}
+
+impl Lazy {
+    /// Decoded hexdump of the instr at `addr`, decoding it only the first time
+    pub fn get(&self, hex: &str, addr: usize) -> Option<&Decoded> {
+        self.0.get_or_init(|| decode_dump(hex, addr, DEFAULT_BITNESS)).as_ref()
+    }
+}
+
+impl Instr {
+    /// Builds the instr, the hexdump is decoded as the code of default bitness
+    pub fn new(addr: usize, hex: String, text: String, isbr: bool) -> Instr {
+        Instr {
+            addr,
+            hex,
+            text,
+            isbr,
+            decoded: Lazy::default(),
+        }
+    }
+
+    /// Builds the instr decoded elsewhere, e.g. as the code of other bitness
+    pub fn with_decoded(addr: usize, hex: String, text: String, isbr: bool, decoded: Decoded) -> Instr {
+        Instr {
+            addr,
+            hex,
+            text,
+            isbr,
+            decoded: Lazy(OnceCell::from(Some(decoded))),
+        }
+    }
+
+    pub fn decoded(&self) -> Option<&Decoded> {
+        self.decoded.get(&self.hex, self.addr)
+    }
+
+    /// Control-flow class and the direct target
+    ///
+    /// They are decoded from the hexdump, if there is none the text is parsed.
+    pub fn flow(&self) -> (Flow, Option<usize>) {
+        match self.decoded() {
+            Some(d) => (d.flow, d.target),
+            None => disasm::parse_flow(&self.text),
+        }
+    }
+
+    /// Address of the following instr, if the hexdump is known
+    pub fn next(&self) -> Option<usize> {
+        if self.hex.is_empty() {
+            None
+        } else {
+            Some(self.addr + self.hex.len() / 2)
+        }
     }
 }
 
 impl Block {
     pub fn split(mut self, addr: usize) -> Result<(Block, Block), Block> {
-        match self.instrs.iter().position(|ref x| x.addr == addr) {
+        match self.instrs.iter().position(|x| x.addr == addr) {
             Some(i) => {
                 let l = self.instrs.drain(0..i).collect();
                 let r = self.instrs;
@@ -50,10 +127,6 @@ impl Block {
             None => Err(Block { instrs: self.instrs }),
         }
     }
-
-    pub fn last(&self) -> Option<usize> {
-        self.instrs.iter().last().map(|x| x.addr)
-    }
 }
 
 #[cfg(test)]
@@ -62,21 +135,27 @@ mod test {
     // TODO move it out
     macro_rules! new_instr {
         ($e:expr) => (
-                Instr {
-                    addr: $e,
-                    hex: String::new(),
-                    text: String::new(),
-                    isbr: false,
-                }
+                Instr::new($e, String::new(), String::new(), false)
             )
     }
 
+    #[test]
+    fn decode() {
+        assert!(new_instr!(0x1000).decoded().is_none());
+        let i = Instr::new(0x1000, "7410".to_string(), String::new(), true);
+        assert!(i.decoded.0.get().is_none());
+        let d = i.decoded().unwrap();
+        assert!(i.decoded.0.get().is_some());
+        assert_eq!((d.mnemonic.as_str(), d.target), ("je", Some(0x1012)));
+        assert!(Instr::new(0x1000, "741".to_string(), String::new(), true).decoded().is_none());
+    }
+
     #[test]
     fn split() {
         let block = Block { instrs: (10..18).map(|x| new_instr!(x)).collect() };
         let (l, r) = block.split(14).unwrap();
         let check = |bb: &Block, rng| {
-            bb.instrs.iter().map(|ref x| x.addr).zip(rng).any(
+            bb.instrs.iter().map(|x| x.addr).zip(rng).any(
                 |(x, y)| x == y,
             )
         };
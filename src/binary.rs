@@ -0,0 +1,436 @@
+//! Compact binary trace format
+//!
+//! The file starts with `MAGIC` and a version byte, followed by tagged records.
+//! Every distinct instruction (address and bytes) is defined once by an
+//! `INSTR` record and then referenced by index from `STEP` records, one per
+//! executed instruction. Names of foreign targets are stored in the same way.
+//! Memory accessed by the step follows it, if the trace records it.
+//! All the integers are LEB128 encoded.
+
+use parsing::{ErrorKind, Mode, ParseError};
+use trace::{MemAccess, TraceStmt};
+use base::{ForeignInfo, Lazy};
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+
+pub const MAGIC: &[u8] = b"\xb7TRC";
+/// Version 3 adds memory accesses, version 2 thread ids, the older ones
+/// are still readable
+pub const VERSION: u8 = 3;
+
+const INSTR: u8 = 1;
+const NAME: u8 = 2;
+const STEP: u8 = 3;
+
+const FLAG_BRANCH: u8 = 1;
+const FLAG_FOREIGN: u8 = 2;
+const FLAG_THREAD: u8 = 4;
+const FLAG_MEMORY: u8 = 8;
+
+fn write_uint<W: Write>(out: &mut W, mut v: u64) -> io::Result<()> {
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            return out.write_all(&[b]);
+        }
+        out.write_all(&[b | 0x80])?;
+    }
+}
+
+fn write_bytes<W: Write>(out: &mut W, b: &[u8]) -> io::Result<()> {
+    write_uint(out, b.len() as u64)?;
+    out.write_all(b)
+}
+
+/// Bytes of the hexdump, `None` if it is malformed
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn encode_hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02X}", x)).collect()
+}
+
+/// Serializes statements into the binary format
+pub struct Writer<W: Write> {
+    out: W,
+    instrs: HashMap<(usize, String), u64>,
+    names: HashMap<String, u64>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut out: W) -> io::Result<Writer<W>> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(Writer {
+            out,
+            instrs: HashMap::new(),
+            names: HashMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, stmt: &TraceStmt) -> io::Result<()> {
+        let key = (stmt.addr, stmt.hex.clone());
+        let idx = match self.instrs.get(&key) {
+            Some(&idx) => idx,
+            None => {
+                let bytes = decode_hex(&stmt.hex).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("wrong hexdump at {:x}: {}", stmt.addr, stmt.hex),
+                    )
+                })?;
+                self.out.write_all(&[INSTR])?;
+                write_uint(&mut self.out, stmt.addr as u64)?;
+                write_bytes(&mut self.out, &bytes)?;
+                write_bytes(&mut self.out, stmt.text.as_bytes())?;
+                let idx = self.instrs.len() as u64;
+                self.instrs.insert(key, idx);
+                idx
+            }
+        };
+        let name = match stmt.foreign {
+            Some(ref f) if !self.names.contains_key(&f.foreign_name) => {
+                self.out.write_all(&[NAME])?;
+                write_bytes(&mut self.out, f.foreign_name.as_bytes())?;
+                let idx = self.names.len() as u64;
+                self.names.insert(f.foreign_name.clone(), idx);
+                Some(idx)
+            }
+            Some(ref f) => Some(self.names[&f.foreign_name]),
+            None => None,
+        };
+
+        let mut flags = 0;
+        if stmt.isbr {
+            flags |= FLAG_BRANCH;
+        }
+        if name.is_some() {
+            flags |= FLAG_FOREIGN;
+        }
+        if stmt.tid.is_some() {
+            flags |= FLAG_THREAD;
+        }
+        if !stmt.memory.is_empty() {
+            flags |= FLAG_MEMORY;
+        }
+        self.out.write_all(&[STEP])?;
+        write_uint(&mut self.out, idx)?;
+        self.out.write_all(&[flags])?;
+        if let (Some(f), Some(name)) = (stmt.foreign.as_ref(), name) {
+            write_uint(&mut self.out, f.foreign_addr as u64)?;
+            write_uint(&mut self.out, name)?;
+        }
+        if let Some(tid) = stmt.tid {
+            write_uint(&mut self.out, tid as u64)?;
+        }
+        if !stmt.memory.is_empty() {
+            write_uint(&mut self.out, stmt.memory.len() as u64)?;
+            for m in stmt.memory.iter() {
+                write_uint(&mut self.out, m.addr as u64)?;
+                // Size along with the direction in the lowest bit
+                write_uint(&mut self.out, (m.size as u64) << 1 | m.write as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+struct Instr {
+    addr: usize,
+    hex: String,
+    text: String,
+}
+
+/// Incremental reader of the binary trace
+pub struct Reader<R> {
+    input: R,
+    mode: Mode,
+    started: bool,
+    done: bool,
+    instrs: Vec<Instr>,
+    names: Vec<String>,
+    /// Index of the current step
+    index: usize,
+    /// Number of consumed bytes
+    offset: usize,
+    /// Offset of the current record
+    start: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R, mode: Mode) -> Reader<R> {
+        Reader {
+            input,
+            mode,
+            started: false,
+            done: false,
+            instrs: Vec::new(),
+            names: Vec::new(),
+            index: 0,
+            offset: 0,
+            start: 0,
+        }
+    }
+
+    /// Reads a byte, `None` means the end of input
+    fn next_byte(&mut self) -> Result<Option<u8>, ErrorKind> {
+        let b = match self.input.fill_buf() {
+            Ok(buf) => buf.first().cloned(),
+            Err(e) => return Err(ErrorKind::Io(e.to_string())),
+        };
+        if b.is_some() {
+            self.input.consume(1);
+            self.offset += 1;
+        }
+        Ok(b)
+    }
+
+    fn byte(&mut self) -> Result<u8, ErrorKind> {
+        self.next_byte()?.ok_or(ErrorKind::Truncated)
+    }
+
+    fn uint(&mut self) -> Result<u64, ErrorKind> {
+        let mut v = 0;
+        for shift in (0..10).map(|x| 7 * x) {
+            let b = self.byte()?;
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(ErrorKind::Corrupted("too long integer".to_string()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, ErrorKind> {
+        let len = self.uint()?;
+        // The length comes from the input, so read what is there instead of reserving it upfront
+        let mut v = Vec::new();
+        (&mut self.input)
+            .take(len)
+            .read_to_end(&mut v)
+            .map_err(|e| ErrorKind::Io(e.to_string()))?;
+        self.offset += v.len();
+        if v.len() as u64 != len {
+            return Err(ErrorKind::Truncated);
+        }
+        Ok(v)
+    }
+
+    fn string(&mut self) -> Result<String, ErrorKind> {
+        String::from_utf8(self.bytes()?).map_err(|e| ErrorKind::Corrupted(e.to_string()))
+    }
+
+    fn header(&mut self) -> Result<(), ErrorKind> {
+        for &m in MAGIC {
+            if self.next_byte()? != Some(m) {
+                return Err(ErrorKind::UnknownFormat);
+            }
+        }
+        match self.byte()? {
+            1..=VERSION => Ok(()),
+            v => Err(ErrorKind::Version(v as usize)),
+        }
+    }
+
+    /// Reads records up to the next step, `None` means the end of input
+    fn step(&mut self) -> Result<Option<TraceStmt>, ErrorKind> {
+        if !self.started {
+            self.header()?;
+            self.started = true;
+        }
+        loop {
+            self.start = self.offset;
+            match self.next_byte()? {
+                None => return Ok(None),
+                Some(INSTR) => {
+                    let addr = self.uint()? as usize;
+                    let hex = encode_hex(&self.bytes()?);
+                    let text = self.string()?;
+                    self.instrs.push(Instr {
+                        addr,
+                        hex,
+                        text,
+                    });
+                }
+                Some(NAME) => {
+                    let name = self.string()?;
+                    self.names.push(name);
+                }
+                Some(STEP) => {
+                    let idx = self.uint()? as usize;
+                    let flags = self.byte()?;
+                    let foreign = if flags & FLAG_FOREIGN != 0 {
+                        let addr = self.uint()? as usize;
+                        let name = self.uint()? as usize;
+                        let name = self.names.get(name).ok_or_else(|| {
+                            ErrorKind::Corrupted(format!("unknown name {}", name))
+                        })?;
+                        Some(ForeignInfo {
+                            foreign_addr: addr,
+                            foreign_name: name.clone(),
+                            stub: false,
+                        })
+                    } else {
+                        None
+                    };
+                    let tid = if flags & FLAG_THREAD != 0 {
+                        Some(self.uint()? as usize)
+                    } else {
+                        None
+                    };
+                    let mut memory = Vec::new();
+                    if flags & FLAG_MEMORY != 0 {
+                        for _ in 0..self.uint()? {
+                            let addr = self.uint()? as usize;
+                            let size = self.uint()?;
+                            memory.push(MemAccess {
+                                addr,
+                                size: (size >> 1) as usize,
+                                write: size & 1 != 0,
+                            });
+                        }
+                    }
+                    let instr = self.instrs.get(idx).ok_or_else(|| {
+                        ErrorKind::Corrupted(format!("unknown instruction {}", idx))
+                    })?;
+                    return Ok(Some(TraceStmt {
+                        addr: instr.addr,
+                        hex: instr.hex.clone(),
+                        text: instr.text.clone(),
+                        isbr: flags & FLAG_BRANCH != 0,
+                        foreign,
+                        tid,
+                        memory,
+                        decoded: Lazy::default(),
+                    }));
+                }
+                Some(tag) => return Err(ErrorKind::Corrupted(format!("unknown record {}", tag))),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<TraceStmt, ParseError>;
+
+    fn next(&mut self) -> Option<Result<TraceStmt, ParseError>> {
+        if self.done {
+            return None;
+        }
+        let index = self.index;
+        self.index += 1;
+        match self.step() {
+            Ok(Some(s)) => return Some(Ok(s)),
+            Ok(None) => {}
+            Err(kind) => {
+                let e = ParseError {
+                    index,
+                    offset: self.start,
+                    kind,
+                };
+                if self.mode == Mode::Lenient && e.kind == ErrorKind::Truncated {
+                    eprintln!("Warning: {}. Stopping...", e);
+                } else {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use binary::*;
+    use parsing::test::traces;
+
+    fn encode(stmts: &[TraceStmt]) -> Vec<u8> {
+        let mut w = Writer::new(Vec::new()).unwrap();
+        for s in stmts {
+            w.write(s).unwrap();
+        }
+        w.into_inner()
+    }
+
+    fn foreign_traces() -> Vec<TraceStmt> {
+        let mut v = traces();
+        v.extend(traces());
+        v[2].foreign = Some(ForeignInfo {
+            foreign_addr: 4195398,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        v[4].tid = Some(7);
+        v[5].memory = vec![
+            MemAccess {
+                addr: 0x7ffe0000,
+                size: 8,
+                write: true,
+            },
+            MemAccess {
+                addr: 0x601040,
+                size: 4,
+                write: false,
+            },
+        ];
+        v
+    }
+
+    #[test]
+    fn roundtrip() {
+        let v = foreign_traces();
+        let data = encode(&v);
+        let res = Reader::new(&data[..], Mode::Strict).collect::<Result<Vec<_>, _>>();
+        assert_eq!(res.unwrap(), v);
+    }
+
+    #[test]
+    fn instruction_table() {
+        let mut v = traces();
+        v.extend(traces());
+        // Repeated instructions cost only the step records: tag, index, flags
+        assert_eq!(encode(&v).len() - encode(&traces()).len(), 3 * traces().len());
+    }
+
+    #[test]
+    fn truncated() {
+        let data = encode(&traces());
+        let data = &data[..data.len() - 1];
+        let res: Vec<_> = Reader::new(data, Mode::Strict).collect();
+        assert_eq!(res.len(), 7);
+        assert_eq!(res[6].clone().unwrap_err().kind, ErrorKind::Truncated);
+        assert_eq!(Reader::new(data, Mode::Lenient).count(), 6);
+    }
+
+    #[test]
+    fn huge_length() {
+        let data = [0xff, 0xff, 0xff, 0xff, 0x0f, 1, 2];
+        let mut r = Reader::new(&data[..], Mode::Strict);
+        assert_eq!(r.bytes(), Err(ErrorKind::Truncated));
+        assert_eq!(r.offset, data.len());
+    }
+
+    #[test]
+    fn version() {
+        let mut data = encode(&traces());
+        data[MAGIC.len()] = VERSION + 1;
+        let res: Vec<_> = Reader::new(&data[..], Mode::Lenient).collect();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].clone().unwrap_err().kind, ErrorKind::Version(VERSION as usize + 1));
+    }
+}
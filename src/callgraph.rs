@@ -0,0 +1,141 @@
+//! Dynamic call graph
+//!
+//! It is derived from the recovered functions: every stub or foreign node of
+//! a function graph is a callee, the blocks jumping into it are call sites.
+
+use cfg::NodeBase;
+use disasm::Flow;
+use func::Functions;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Node {
+    /// Recovered function by its entry
+    Function(usize),
+    /// Foreign code by its name
+    Foreign(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub count: usize,
+    /// Addresses of the call instrs
+    pub sites: BTreeSet<usize>,
+    /// Whether any of the sites is an indirect call
+    pub indirect: bool,
+}
+
+pub struct CallGraph {
+    pub nodes: BTreeSet<Node>,
+    /// Calls by the caller and the callee
+    pub calls: BTreeMap<(Node, Node), Call>,
+    /// Observed targets of every indirect call site
+    pub indirect: BTreeMap<usize, BTreeSet<Node>>,
+}
+
+impl CallGraph {
+    pub fn new(funcs: &Functions) -> CallGraph {
+        let mut cg = CallGraph {
+            nodes: BTreeSet::new(),
+            calls: BTreeMap::new(),
+            indirect: BTreeMap::new(),
+        };
+        for (&entry, f) in funcs.funcs.iter() {
+            let caller = Node::Function(entry);
+            cg.nodes.insert(caller.clone());
+            for (s, ts) in f.cfg.edges.iter() {
+                let site = match f.cfg.verts[s].node {
+                    NodeBase::Block(ref b) => b.instrs.last().unwrap(),
+                    _ => continue,
+                };
+                for (t, e) in ts.iter() {
+                    let callee = match f.cfg.verts[t].node {
+                        NodeBase::Foreign(ref x) if x.stub => Node::Function(x.foreign_addr),
+                        NodeBase::Foreign(ref x) => Node::Foreign(x.foreign_name.clone()),
+                        _ => continue,
+                    };
+                    let indirect = matches!(site.flow().0, Flow::IndirectCall | Flow::IndirectJump);
+                    if indirect {
+                        cg.indirect
+                            .entry(site.addr)
+                            .or_default()
+                            .insert(callee.clone());
+                    }
+                    cg.nodes.insert(callee.clone());
+                    let call = cg.calls.entry((caller.clone(), callee)).or_insert(Call {
+                        count: 0,
+                        sites: BTreeSet::new(),
+                        indirect: false,
+                    });
+                    call.count += e.count;
+                    call.sites.insert(site.addr);
+                    call.indirect |= indirect;
+                }
+            }
+        }
+        cg
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use callgraph::*;
+    use base::ForeignInfo;
+    use trace::Bb;
+    use trace::test::stmt;
+
+    #[test]
+    fn calls() {
+        // ffb: call puts; 1000: call rax; 1002: jmp 0x1000
+        let mut puts = stmt(0xffb, "E800000000", "call 0x1000");
+        puts.foreign = Some(ForeignInfo {
+            foreign_addr: 0x7f0000001000,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        let mut trace = vec![puts];
+        for &callee in [0x2000, 0x3000, 0x2000].iter() {
+            trace.push(stmt(0x1000, "FFD0", "call rax"));
+            trace.push(stmt(callee, "C3", "ret"));
+            trace.push(stmt(0x1002, "EBFC", "jmp 0x1000"));
+        }
+        let cg = CallGraph::new(&Functions::from_blocks(Bb::new(trace)));
+
+        let main = Node::Function(0xffb);
+        assert_eq!(cg.nodes.len(), 4);
+        let call = &cg.calls[&(main.clone(), Node::Function(0x2000))];
+        assert_eq!(call.count, 2);
+        assert_eq!(call.sites, vec![0x1000].into_iter().collect());
+        assert!(call.indirect);
+        assert_eq!(cg.calls[&(main.clone(), Node::Function(0x3000))].count, 1);
+        let call = &cg.calls[&(main.clone(), Node::Foreign("puts".to_string()))];
+        assert_eq!((call.count, call.indirect), (1, false));
+        assert_eq!(
+            cg.indirect[&0x1000],
+            vec![Node::Function(0x2000), Node::Function(0x3000)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn recursion() {
+        // 1000: call 0x2000; 1005: ret; 2000: jz 0x2007; 2002: call 0x2000; 2007: ret
+        let jz = stmt(0x2000, "7405", "jz 0x2007");
+        let ret = stmt(0x2007, "C3", "ret");
+        let trace = vec![
+            stmt(0x1000, "E8FB0F0000", "call 0x2000"),
+            jz.clone(),
+            stmt(0x2002, "E8F9FFFFFF", "call 0x2000"),
+            jz,
+            ret.clone(),
+            ret,
+            stmt(0x1005, "C3", "ret"),
+        ];
+        let cg = CallGraph::new(&Functions::from_blocks(Bb::new(trace)));
+        let sub = Node::Function(0x2000);
+        assert_eq!(cg.nodes.len(), 2);
+        let call = &cg.calls[&(sub.clone(), sub)];
+        assert_eq!((call.count, call.indirect), (1, false));
+        assert_eq!(call.sites, vec![0x2002].into_iter().collect());
+    }
+}
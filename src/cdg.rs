@@ -0,0 +1,90 @@
+//! Control dependence graph
+//!
+//! A vertex is control dependent on the branches in its post-dominance
+//! frontier: some successor of such a branch always leads to the vertex,
+//! while the others may avoid it.
+
+use cfg::{Cfg, Key};
+use dom::Dominators;
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub struct Cdg {
+    /// Branches every vertex is directly control dependent on
+    pub deps: BTreeMap<Key, BTreeSet<Key>>,
+    /// Successors of the branch leading to the dependent vertex
+    pub edges: BTreeMap<(Key, Key), BTreeSet<Key>>,
+}
+
+impl Cdg {
+    pub fn new(cfg: &Cfg, pdom: &Dominators) -> Cdg {
+        let mut cdg = Cdg {
+            deps: BTreeMap::new(),
+            edges: BTreeMap::new(),
+        };
+        for (&x, frontier) in pdom.frontiers.iter() {
+            for &b in frontier.iter() {
+                let succs = cfg.succs(b).into_iter().filter(|&s| pdom.dominates(x, s)).collect();
+                cdg.deps.entry(x).or_default().insert(b);
+                cdg.edges.insert((b, x), succs);
+            }
+        }
+        cdg
+    }
+
+    /// Branches deciding whether `x` executes along with the distance to it
+    ///
+    /// The direct ones are 1, then the ones deciding whether they execute.
+    pub fn deciders(&self, x: Key) -> BTreeMap<Key, usize> {
+        let mut res = BTreeMap::new();
+        let mut next = vec![x];
+        let mut distance = 0;
+        while !next.is_empty() {
+            distance += 1;
+            let mut found = Vec::new();
+            for y in next {
+                for &b in self.deps.get(&y).into_iter().flat_map(|d| d.iter()) {
+                    if let Entry::Vacant(e) = res.entry(b) {
+                        e.insert(distance);
+                        found.push(b);
+                    }
+                }
+            }
+            next = found;
+        }
+        res
+    }
+
+    /// Vertices directly control dependent on the branch `b`
+    pub fn dependents(&self, b: Key) -> BTreeSet<Key> {
+        self.edges.keys().filter(|e| e.0 == b).map(|e| e.1).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cdg::*;
+    use trace::Bb;
+    use trace::test::at;
+
+    #[test]
+    fn dependence() {
+        // 1 -> 2 -> 3 -> 5, 2 -> 4 -> 5, 1 -> 5, 5 -> 1, 5 -> 6
+        let path = vec![1, 2, 3, 5, 1, 2, 4, 5, 1, 5, 6];
+        let cfg = Cfg::from_blocks(Bb::new(path.into_iter().map(at)));
+        let cdg = Cdg::new(&cfg, &Dominators::post(&cfg));
+        let k = Key::from;
+        let keys = |v: Vec<usize>| v.into_iter().map(k).collect::<BTreeSet<_>>();
+
+        assert_eq!(cdg.deps[&k(3)], keys(vec![2]));
+        assert_eq!(cdg.deps[&k(2)], keys(vec![1]));
+        assert_eq!(cdg.deps[&k(1)], keys(vec![5]));
+        assert!(!cdg.deps.contains_key(&k(6)));
+        assert_eq!(cdg.edges[&(k(2), k(4))], keys(vec![4]));
+        assert_eq!(cdg.dependents(k(2)), keys(vec![3, 4]));
+
+        let deciders: Vec<(usize, usize)> = cdg.deciders(k(3)).into_iter().map(|(b, d)| (b.addr, d)).collect();
+        assert_eq!(deciders, vec![(1, 2), (2, 1), (5, 3)]);
+    }
+}
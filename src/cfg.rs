@@ -1,7 +1,8 @@
-use itertools::Itertools;
-use std::collections::{HashMap, BTreeMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
+use std::fmt;
 use trace::Bb;
-use base::{Addressable, Block, ForeignInfo};
+use base::{Addressable, Block, ForeignInfo, Instr};
+use disasm::Flow;
 
 #[derive(Debug)]
 pub enum NodeBase<B, F> {
@@ -11,14 +12,149 @@ pub enum NodeBase<B, F> {
 
 pub type Node = NodeBase<Block, ForeignInfo>;
 
+/// Vertex key, the address along with the generation of the code at it
+///
+/// Foreign nodes and the code never rewritten are generation 0, the stubs
+/// of the calls are `STUB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key {
+    pub addr: usize,
+    pub gen: usize,
+}
+
+/// Generation of the stubs, so that a call never collides with the callee entry
+pub const STUB: usize = usize::MAX;
+
+impl Key {
+    pub fn new(addr: usize, gen: usize) -> Key {
+        Key {
+            addr,
+            gen,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.gen {
+            0 => write!(f, "{:x}", self.addr),
+            STUB => write!(f, "{:x}.stub", self.addr),
+            gen => write!(f, "{:x}.{}", self.addr, gen),
+        }
+    }
+}
+
+impl From<usize> for Key {
+    fn from(addr: usize) -> Key {
+        Key::new(addr, 0)
+    }
+}
+
 #[derive(Debug)]
 pub struct VisitingNode {
     pub node: Node,
+    /// Number of executions, 0 if unknown
+    pub count: usize,
+    /// Trace index of the first and the last execution
+    pub first: usize,
+    pub last: usize,
 }
 
 impl VisitingNode {
     fn from_node(n: Node) -> VisitingNode {
-        VisitingNode { node: n }
+        VisitingNode {
+            node: n,
+            count: 0,
+            first: 0,
+            last: 0,
+        }
+    }
+
+    fn visit(&mut self, index: usize) {
+        if self.count == 0 {
+            self.first = index;
+        }
+        self.count += 1;
+        self.last = index;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    /// Next instr after the block, either not a branch or a not taken one
+    Fallthrough,
+    /// Taken conditional branch
+    Taken,
+    /// Direct unconditional jump
+    Jump,
+    /// Direct call
+    Call,
+    /// Call or jump through a register or memory
+    Indirect,
+    Return,
+    /// Transfer into or out of the foreign code
+    Foreign,
+}
+
+impl EdgeKind {
+    /// Kind of the transfer from the terminating `instr` of a block to `target`
+    pub fn classify(instr: &Instr, target: usize) -> EdgeKind {
+        let (flow, direct) = instr.flow();
+        let next = instr.next();
+        match flow {
+            Flow::Return => EdgeKind::Return,
+            Flow::Call => EdgeKind::Call,
+            Flow::Jump => EdgeKind::Jump,
+            Flow::IndirectCall | Flow::IndirectJump => EdgeKind::Indirect,
+            Flow::Conditional if direct == Some(target) && next != Some(target) => EdgeKind::Taken,
+            Flow::Conditional => EdgeKind::Fallthrough,
+            _ if next == Some(target) => EdgeKind::Fallthrough,
+            _ => EdgeKind::Jump,
+        }
+    }
+}
+
+impl fmt::Display for EdgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Taken => "taken",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Call => "call",
+            EdgeKind::Indirect => "indirect",
+            EdgeKind::Return => "return",
+            EdgeKind::Foreign => "foreign",
+        })
+    }
+}
+
+/// Control transfer observed in the trace
+///
+/// Trace index of a transfer is the one of the first statement of the
+/// target block, or of the branch for a foreign target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub count: usize,
+    pub first: usize,
+    pub last: usize,
+}
+
+impl Edge {
+    pub fn new(kind: EdgeKind, index: usize) -> Edge {
+        Edge {
+            kind,
+            count: 1,
+            first: index,
+            last: index,
+        }
+    }
+
+    /// Accounts the transfers of `other` in this edge
+    pub fn merge(&mut self, other: &Edge) {
+        self.count += other.count;
+        self.first = self.first.min(other.first);
+        self.last = self.last.max(other.last);
     }
 }
 
@@ -33,99 +169,244 @@ impl Addressable for VisitingNode {
 
 #[derive(Debug)]
 pub struct Cfg {
-    pub verts: BTreeMap<usize, VisitingNode>,
-    pub edges: HashMap<usize, HashSet<usize>>,
+    pub verts: BTreeMap<Key, VisitingNode>,
+    pub edges: HashMap<Key, HashMap<Key, Edge>>,
+    /// Last pushed node of every thread
+    last: HashMap<Option<usize>, Key>,
+    /// Extra lines of the vertex labels in the rendering
+    pub notes: BTreeMap<Key, Vec<String>>,
+    /// Addresses of the instrs highlighted in the rendering
+    pub marks: BTreeSet<usize>,
 }
 
 impl Cfg {
-    pub fn from_blocks(v: Vec<Bb>) -> Cfg {
-        let nodes: Vec<(usize, VisitingNode)> = v.into_iter()
-            .fold(Vec::new(), |mut acc, x| {
-                let (b, f) = x.separate();
-                eprintln!("blk: {}..{}", b.addr().unwrap(), b.last().unwrap());
-                acc.push((b.addr().unwrap(), NodeBase::Block(b)));
-                if let Some(f) = f {
-                    eprintln!("for: {}", f.foreign_name);
-                    acc.push((f.addr().unwrap(), NodeBase::Foreign(f)));
-                }
-                acc
-            })
-            .into_iter()
-            .map(|(x, y)| (x, VisitingNode::from_node(y)))
-            .collect();
-        // Grub consequetive pairs of nodes (0,1), (1,2), ...
-        let mut edges: Vec<(usize, usize)> =
-            nodes.iter().map(|&(x, _)| x).tuple_windows().collect();
-        edges.sort_by(|&(x, _), &(y, _)| x.cmp(&y));
-        edges.dedup();
-        // Group edges by the source vert
-        //  [0 -> 1, 0 -> 2, 1 -> 2, 3 -> 4]
-        // is becoming
-        //  [[0 -> 1, 0 -> 2], [1 -> 2], [3 -> 4]]
-        let edges: Vec<Vec<(usize, usize)>> =
-            edges.into_iter().fold(vec![Vec::new()], |mut acc, x| {
-                let last = acc.len() - 1;
-                if if let Some(&(t, _)) = acc[last].last() {
-                    t == x.0
-                } else {
-                    false
-                }
-                {
-                    acc.push(Vec::new());
-                };
-                acc[last].push(x);
-                acc
-            });
-        let mut cfg = Cfg {
-            verts: nodes.into_iter().collect(),
-            edges: edges.into_iter().fold(HashMap::new(), |mut acc, x| {
-                for (l, r) in x {
-                    let set = acc.entry(l).or_insert(HashSet::new());
-                    set.insert(r);
-                }
-                acc
-            }),
-        };
-        for addr in cfg.find_dups() {
-            eprintln!("split: {:?}", addr);
-            cfg.split(addr).unwrap();
+    pub fn new() -> Cfg {
+        Cfg {
+            verts: BTreeMap::new(),
+            edges: HashMap::new(),
+            last: HashMap::new(),
+            notes: BTreeMap::new(),
+            marks: BTreeSet::new(),
         }
+    }
+
+    pub fn from_blocks<I: IntoIterator<Item = Bb>>(v: I) -> Cfg {
+        let mut cfg = Cfg::new();
+        for x in v {
+            cfg.push(x);
+        }
+        cfg.finish();
         cfg
     }
 
-    fn find_dups(&self) -> Vec<usize> {
+    /// Builds the combined graph along with a graph per every thread
+    pub fn from_blocks_per_thread<I: IntoIterator<Item = Bb>>(
+        v: I,
+    ) -> (Cfg, BTreeMap<Option<usize>, Cfg>) {
+        let mut cfg = Cfg::new();
+        let mut threads = BTreeMap::new();
+        for x in v {
+            threads.entry(x.tid()).or_insert_with(Cfg::new).push(x.clone());
+            cfg.push(x);
+        }
+        cfg.finish();
+        for t in threads.values_mut() {
+            t.finish();
+        }
+        (cfg, threads)
+    }
+
+    /// Adds the next traced block
+    ///
+    /// It is connected with the previous node of the same thread, so the
+    /// graph may be built while the trace is still being read.
+    pub fn push(&mut self, x: Bb) {
+        let tid = x.tid();
+        let (index, last, gen) = (x.index, x.last, x.gen);
+        let (b, f) = x.separate();
+        let mut nodes = vec![(NodeBase::Block(b), gen, index)];
+        if let Some(f) = f {
+            let gen = if f.stub { STUB } else { 0 };
+            nodes.push((NodeBase::Foreign(f), gen, last));
+        }
+        // Connect consequetive nodes (0,1), (1,2), ...
+        for (n, gen, index) in nodes {
+            let node = VisitingNode::from_node(n);
+            let key = Key::new(node.addr().unwrap(), gen);
+            if let Some(p) = self.last.insert(tid, key) {
+                let edge = Edge::new(self.kind(p, &node), index);
+                self.edges
+                    .entry(p)
+                    .or_default()
+                    .entry(key)
+                    .and_modify(|e| e.merge(&edge))
+                    .or_insert(edge);
+            }
+            self.verts.entry(key).or_insert(node).visit(index);
+        }
+    }
+
+    /// Replaces the node the next pushed one of the thread is connected with
+    ///
+    /// The replaced one is returned, so that the path may be resumed later.
+    pub fn resume(&mut self, tid: Option<usize>, last: Option<Key>) -> Option<Key> {
+        match last {
+            Some(x) => self.last.insert(tid, x),
+            None => self.last.remove(&tid),
+        }
+    }
+
+    /// First traced vertex, the least one if no vertex has been traced
+    pub fn entry(&self) -> Option<Key> {
         self.verts
             .iter()
-            .filter_map(|(&x, ref y)| {
-                if x == 0 {
-                    return None;
-                }
-                if let Some(&NodeBase::Block(ref n)) =
-                    self.verts.range(..x - 1).last().map(|(&_, ref y)| &y.node)
-                {
-                    let addr = y.addr().unwrap();
-                    if let Some(s) = n.instrs.iter().find(|&x| x.addr == addr) {
-                        eprintln!("{:?}", s);
-                        return Some(addr);
+            .min_by_key(|&(&k, v)| (v.count == 0, v.first, k))
+            .map(|(&k, _)| k)
+    }
+
+    /// Vertices the traced paths end in
+    ///
+    /// They are the ones without successors and the last ones of every thread.
+    pub fn exits(&self) -> BTreeSet<Key> {
+        let mut res: BTreeSet<Key> = self.verts
+            .keys()
+            .filter(|k| self.edges.get(k).map_or(true, |x| x.is_empty()))
+            .cloned()
+            .collect();
+        res.extend(self.last.values().cloned());
+        res
+    }
+
+    /// Successors of the vertex
+    pub fn succs(&self, k: Key) -> BTreeSet<Key> {
+        self.edges.get(&k).map_or_else(BTreeSet::new, |x| x.keys().cloned().collect())
+    }
+
+    /// Predecessors of every vertex having any
+    pub fn preds(&self) -> BTreeMap<Key, BTreeSet<Key>> {
+        let mut res = BTreeMap::new();
+        for (&s, ts) in self.edges.iter() {
+            for &t in ts.keys() {
+                res.entry(t).or_insert_with(BTreeSet::new).insert(s);
+            }
+        }
+        res
+    }
+
+    /// Vertices the traced block runs through, it may have been split
+    pub fn walk(&self, bb: &Bb) -> Vec<Key> {
+        let mut res = Vec::new();
+        let mut key = match bb.addr() {
+            Some(addr) => Key::new(addr, bb.gen),
+            None => return res,
+        };
+        let mut pos = 0;
+        while let Some(NodeBase::Block(b)) = self.verts.get(&key).map(|x| &x.node) {
+            res.push(key);
+            pos += b.instrs.len();
+            let next = bb.stmts.get(pos).and_then(|s| {
+                self.succs(key).into_iter().find(|k| k.addr == s.addr)
+            });
+            match next {
+                Some(k) => key = k,
+                None => break,
+            }
+        }
+        res
+    }
+
+    /// Kind of the transfer from the vertex `from` to `to`
+    fn kind(&self, from: Key, to: &VisitingNode) -> EdgeKind {
+        match (self.verts.get(&from).map(|x| &x.node), &to.node) {
+            (Some(NodeBase::Block(b)), &NodeBase::Block(_)) => {
+                b.instrs.last().map_or(EdgeKind::Jump, |x| {
+                    EdgeKind::classify(x, to.addr().unwrap())
+                })
+            }
+            // Calls of the traced functions stand for a call and a return
+            (Some(NodeBase::Block(b)), NodeBase::Foreign(f)) if f.stub => {
+                b.instrs.last().map_or(EdgeKind::Call, |x| EdgeKind::classify(x, f.foreign_addr))
+            }
+            (Some(NodeBase::Foreign(f)), _) if f.stub => EdgeKind::Return,
+            _ => EdgeKind::Foreign,
+        }
+    }
+
+    /// Splits the blocks which other blocks jump into
+    pub fn finish(&mut self) {
+        for (key, addr) in self.find_dups() {
+            self.split(key, addr).unwrap();
+        }
+    }
+
+    /// Blocks along with the addresses other blocks start in the middle of
+    ///
+    /// Only the blocks starting with the same bytes count, so the code
+    /// rewritten or decoded at an odd offset is not split. The addresses of
+    /// a block come last first, so that the earlier splits keep its key.
+    fn find_dups(&self) -> Vec<(Key, usize)> {
+        let starts: HashSet<(usize, &str)> = self.verts
+            .values()
+            .filter_map(|v| match v.node {
+                NodeBase::Block(ref b) => b.instrs.first().map(|x| (x.addr, x.hex.as_str())),
+                _ => None,
+            })
+            .collect();
+        let mut res = Vec::new();
+        for (&key, v) in self.verts.iter() {
+            if let NodeBase::Block(ref b) = v.node {
+                for x in b.instrs.iter().skip(1).rev() {
+                    if starts.contains(&(x.addr, x.hex.as_str())) {
+                        res.push((key, x.addr));
                     }
                 }
-                None
-            })
-            .collect()
+            }
+        }
+        res
     }
 
-    fn insert_block(&mut self, block: Block) {
-        let addr = block.addr().unwrap();
+    /// Adds the block of the code never rewritten
+    pub fn insert_block(&mut self, block: Block) -> &mut VisitingNode {
+        let key = Key::from(block.addr().unwrap());
+        self.insert(key, block)
+    }
+
+    fn insert(&mut self, key: Key, block: Block) -> &mut VisitingNode {
         self.verts.insert(
-            addr,
+            key,
             VisitingNode::from_node(NodeBase::Block(block)),
         );
+        self.verts.get_mut(&key).unwrap()
     }
 
-    pub fn split(&mut self, addr: usize) -> Result<(), ()> {
-        let t = *self.verts.range(..addr).last().ok_or(())?.0;
-        let prev = self.verts.remove(&t).unwrap().node;
-        let prev = match prev {
+    /// Key of the block split off at `addr` from the vertex `key`
+    ///
+    /// It is the same block executed on its own if there is one, otherwise
+    /// the generation of the split one is kept unless it is taken.
+    fn split_key(&self, key: Key, block: &Block) -> Key {
+        let addr = block.addr().unwrap();
+        let mut gens = self.verts.range(Key::new(addr, 0)..Key::new(addr, STUB));
+        let same = gens.clone().find(|&(_, v)| match v.node {
+            NodeBase::Block(ref b) => same_code(b, block),
+            _ => false,
+        });
+        match same {
+            Some((&k, _)) => k,
+            None if !self.verts.contains_key(&Key::new(addr, key.gen)) => Key::new(addr, key.gen),
+            None => Key::new(addr, gens.next_back().unwrap().0.gen + 1),
+        }
+    }
+
+    /// Splits the block of the vertex `key` at `addr`
+    pub fn split(&mut self, key: Key, addr: usize) -> Result<(), ()> {
+        let prev = self.verts.remove(&key).ok_or(())?;
+        let visits = (prev.count, prev.first, prev.last);
+        let set_visits = |n: &mut VisitingNode, (count, first, last)| {
+            n.count = count;
+            n.first = first;
+            n.last = last;
+        };
+        let prev = match prev.node {
             NodeBase::Block(bb) => bb,
             _ => return Err(()),
         };
@@ -134,60 +415,63 @@ impl Cfg {
             // =>
             // A -> block1 {stmts1} -> block2 {stmts2} -> C
             Ok((block1, block2)) => {
-                let (b1, b2) = (block1.addr().unwrap(), block2.addr().unwrap());
-                self.insert_block(block1);
-                self.insert_block(block2);
+                let (b1, b2) = (key, self.split_key(key, &block2));
+                let (count, first, last) = visits;
+                set_visits(self.insert(b1, block1), visits);
+                // block2 may be executed on its own as well
+                let visits2 = match self.verts.get(&b2) {
+                    Some(n) if n.count > 0 => {
+                        (n.count + count, n.first.min(first), n.last.max(last))
+                    }
+                    _ => visits,
+                };
+                set_visits(self.insert(b2, block2), visits2);
 
-                let set = self.edges.remove(&b1).unwrap();
-                // b1 -> b2
-                self.edges.insert(b1, HashSet::new());
-                self.edges.get_mut(&b1).ok_or(())?.insert(b2);
+                let out = self.edges.remove(&b1).unwrap_or_default();
+                // b1 -> b2, taken every time the block is executed
+                let mut fall = HashMap::new();
+                fall.insert(
+                    b2,
+                    Edge {
+                        kind: EdgeKind::Fallthrough,
+                        count,
+                        first,
+                        last,
+                    },
+                );
+                self.edges.insert(b1, fall);
 
                 // B -> C => b2 -> C
-                let old = if let Some(old) = self.edges.get(&b2) {
-                    old.clone()
-                } else {
-                    HashSet::new()
-                };
-                self.edges.insert(b2, old.union(&set).cloned().collect());
+                let old = self.edges.entry(b2).or_default();
+                for (c, e) in out {
+                    old.entry(c).and_modify(|x| x.merge(&e)).or_insert(e);
+                }
+                // The paths ended in B end in b2
+                for x in self.last.values_mut().filter(|x| **x == b1) {
+                    *x = b2;
+                }
             }
-            Err(b) => self.insert_block(b),
+            Err(b) => set_visits(self.insert(key, b), visits),
         }
         Ok(())
     }
+}
 
-    /// Performs replacing blocks `mapping` key with its value
-    /// Additional redirect all the branches to the new block
-    fn merge(&mut self, mapping: HashMap<usize, usize>) {
-        for (&f, &t) in mapping.iter() {
-            eprintln!("merge {:?} -> {:?}", f, t);
-            // Remove the corresponding vert
-            self.verts.remove(&f).unwrap();
-            // Replace edges: old -> _
-            //            to: new -> _
-            if let Some(v) = self.edges.remove(&f) {
-                self.edges.insert(t, v);
-            }
-        }
-
-        // Replace edges: _ -> old
-        //            to: _ -> new
-        for (f, t) in mapping.into_iter() {
-            for (_, v) in self.edges.iter_mut() {
-                if v.remove(&f) {
-                    v.insert(t);
-                }
-            }
-        }
-    }
+/// Whether the blocks consist of the same instrs
+fn same_code(a: &Block, b: &Block) -> bool {
+    a.instrs.len() == b.instrs.len() &&
+        a.instrs.iter().zip(b.instrs.iter()).all(|(x, y)| {
+            x.addr == y.addr && x.hex == y.hex
+        })
 }
 
 #[cfg(test)]
 mod test {
     use itertools::Itertools;
-    use std::collections::HashSet;
+    use std::collections::HashMap;
     use trace::{TraceStmt, Bb};
-    use cfg::Cfg;
+    use cfg::{Cfg, Edge, EdgeKind, Key};
+    use base::{ForeignInfo, Instr};
 
     // TODO move it out
     macro_rules! new_trace {
@@ -198,10 +482,17 @@ mod test {
                     text: String::new(),
                     isbr: false,
                     foreign: None,
+                    tid: None,
+                    memory: Vec::new(),
+                    decoded: Default::default(),
                 }
             )
     }
 
+    fn k(addr: usize) -> Key {
+        Key::from(addr)
+    }
+
     /// Generates the CFG with the following basic blocks (one per line)
     ///  0  1  2  3
     ///  4  5  6  7
@@ -214,49 +505,75 @@ mod test {
                     Bb {
                         stmts: (0..3)
                             .map(|t| 4 * x + t)
-                            .into_iter()
                             .map(|t| new_trace!(t))
                             .collect(),
+                        index: 3 * x,
+                        last: 3 * x + 2,
+                        gen: 0,
                     }
-                })
-                .collect(),
+                }),
         )
     }
 
     #[test]
     fn build() {
         let cfg = make_base_cfg();
-        for (&v, c) in cfg.verts.keys().zip((0..4).map(|x| 4 * x)) {
+        for (&v, c) in cfg.verts.keys().zip((0..4).map(|x| k(4 * x))) {
             assert_eq!(v, c);
         }
-        for (ref c1, ref c2) in (0..3).map(|x| (4 * x, 4 * (x + 1))) {
-            assert!(&cfg.edges[c1].contains(c2));
+        for (ref c1, ref c2) in (0..3).map(|x| (k(4 * x), k(4 * (x + 1)))) {
+            assert!(&cfg.edges[c1].contains_key(c2));
         }
     }
 
+    #[test]
+    fn threads() {
+        // Thread 1 runs 0 -> 8, thread 2 runs 4 -> 12, interleaved
+        let bbs = vec![(0, 1), (4, 2), (8, 1), (12, 2)].into_iter().map(|(a, t)| {
+            let mut s = new_trace!(a);
+            s.tid = Some(t);
+            Bb {
+                stmts: vec![s],
+                index: a / 4,
+                last: a / 4,
+                gen: 0,
+            }
+        });
+        let (cfg, threads) = Cfg::from_blocks_per_thread(bbs);
+        assert_eq!(cfg.verts.len(), 4);
+        assert_eq!(cfg.edges.len(), 2);
+        assert!(cfg.edges[&k(0)].contains_key(&k(8)));
+        assert!(cfg.edges[&k(4)].contains_key(&k(12)));
+
+        assert_eq!(threads.len(), 2);
+        let t1 = &threads[&Some(1)];
+        assert_eq!(t1.verts.keys().cloned().collect::<Vec<_>>(), vec![k(0), k(8)]);
+        assert_eq!(t1.edges.len(), 1);
+    }
+
     #[test]
     fn split() {
         let mut cfg = make_base_cfg();
-        for (&v, c) in cfg.verts.keys().zip((0..4).map(|x| 4 * x)) {
+        for (&v, c) in cfg.verts.keys().zip((0..4).map(|x| k(4 * x))) {
             assert_eq!(v, c);
         }
         let sz = cfg.verts.len();
-        assert!(cfg.split(5).is_ok());
+        assert!(cfg.split(k(4), 5).is_ok());
         assert_eq!(cfg.verts.len(), sz + 1);
-        let vec = vec![0, 4, 5, 8, 12];
+        let vec: Vec<Key> = vec![0, 4, 5, 8, 12].into_iter().map(k).collect();
         for v in vec.iter() {
-            assert!(cfg.verts.contains_key(&v));
+            assert!(cfg.verts.contains_key(v));
         }
         assert_eq!(4, cfg.edges.len());
         for (c1, c2) in vec.iter().tuple_windows() {
-            assert!(cfg.edges[c1].contains(c2));
+            assert!(cfg.edges[c1].contains_key(c2));
         }
     }
 
     #[test]
     fn split_with_loop() {
         let mut cfg = make_base_cfg();
-        for (&v, c) in cfg.verts.keys().zip((0..4).map(|x| 4 * x)) {
+        for (&v, c) in cfg.verts.keys().zip((0..4).map(|x| k(4 * x))) {
             assert_eq!(v, c);
         }
         // Before splitting
@@ -264,31 +581,130 @@ mod test {
         //  4 -> 8,  4 -> 12
         //  8 -> 12, 8 -> 4
         // 12 -> 4
-        cfg.edges.get_mut(&4).unwrap().insert(12);
-        cfg.edges.get_mut(&8).unwrap().insert(4);
-        cfg.edges.insert(12, HashSet::new());
-        cfg.edges.get_mut(&12).unwrap().insert(4);
+        cfg.edges.get_mut(&k(4)).unwrap().insert(k(12), Edge::new(EdgeKind::Taken, 9));
+        cfg.edges.get_mut(&k(8)).unwrap().insert(k(4), Edge::new(EdgeKind::Jump, 3));
+        cfg.edges.insert(k(12), HashMap::new());
+        cfg.edges.get_mut(&k(12)).unwrap().insert(k(4), Edge::new(EdgeKind::Jump, 3));
         // After splitting
         //  0 -> 4
         //  4 -> 5
         //  5 -> 8,  5 -> 12
         //  8 -> 12, 8 -> 4
         // 12 -> 4
-        assert!(cfg.split(5).is_ok());
+        assert!(cfg.split(k(4), 5).is_ok());
         let vec = vec![(0, 4), (4, 5), (5, 8), (5, 12), (8, 12), (8, 4), (12, 4)];
         assert_eq!(5, cfg.edges.len());
-        for (c1, c2) in vec {
-            eprintln!("== {} {} {:?} ==", c1, c2, cfg.edges[&c1]);
-            assert!(cfg.edges[&c1].contains(&c2));
+        for (c1, c2) in vec.into_iter().map(|(x, y)| (k(x), k(y))) {
+            eprintln!("== {:?} {:?} {:?} ==", c1, c2, cfg.edges[&c1]);
+            assert!(cfg.edges[&c1].contains_key(&c2));
         }
     }
 
     #[test]
-    fn merge() {
-        let mut cfg = make_base_cfg();
-        cfg.merge((0..1).map(|_| (4, 8)).collect());
-        eprintln!("{}", cfg);
-        assert_eq!(cfg.verts.len(), 3);
-        assert_eq!(cfg.edges.len(), 2);
+    fn counts() {
+        // 0 -> 4 -> 8 -> 6 -> 8 -> 4 -> 8 -> 12, where 6 is the middle of 4
+        let entries = vec![0, 4, 8, 6, 8, 4, 8, 12];
+        let bbs = entries.into_iter().enumerate().map(|(i, a)| {
+            let mut s = new_trace!(a);
+            s.isbr = true;
+            let mut stmts = vec![s];
+            if a == 4 {
+                stmts.push(new_trace!(6));
+            }
+            Bb {
+                last: i + stmts.len() - 1,
+                stmts,
+                index: i,
+                gen: 0,
+            }
+        });
+        let cfg = Cfg::from_blocks(bbs);
+        assert_eq!(cfg.verts[&k(4)].count, 2);
+        assert_eq!(cfg.verts[&k(6)].count, 3);
+        assert_eq!(cfg.verts[&k(8)].count, 3);
+        assert_eq!((cfg.verts[&k(8)].first, cfg.verts[&k(8)].last), (2, 6));
+        let edge = |kind, count, first, last| {
+            Edge {
+                kind,
+                count,
+                first,
+                last,
+            }
+        };
+        assert_eq!(cfg.edges[&k(4)][&k(6)], edge(EdgeKind::Fallthrough, 2, 1, 5));
+        assert_eq!(cfg.edges[&k(6)][&k(8)], edge(EdgeKind::Jump, 3, 2, 6));
+        assert_eq!(cfg.edges[&k(8)][&k(4)], Edge::new(EdgeKind::Jump, 5));
+        assert_eq!(cfg.edges[&k(8)][&k(6)], Edge::new(EdgeKind::Jump, 3));
+        assert_eq!(cfg.edges[&k(8)][&k(12)], Edge::new(EdgeKind::Jump, 7));
+    }
+
+    #[test]
+    fn foreign_index() {
+        // Thread 1 calls puts at 0x11, thread 2 runs in between
+        let mut trace = vec![new_trace!(0x10), new_trace!(0x20), new_trace!(0x11)];
+        trace[0].tid = Some(1);
+        trace[1].tid = Some(2);
+        trace[1].isbr = true;
+        trace[2].tid = Some(1);
+        trace[2].isbr = true;
+        trace[2].foreign = Some(ForeignInfo {
+            foreign_addr: 0x1000,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        let cfg = Cfg::from_blocks(Bb::new(trace));
+        let puts = &cfg.verts[&k(0x1000)];
+        assert_eq!((puts.first, puts.last), (2, 2));
+        assert_eq!(cfg.edges[&k(0x10)][&k(0x1000)].first, 2);
+    }
+
+    #[test]
+    fn rewritten() {
+        let stmt = |addr, hex: &str, isbr| {
+            let mut s = new_trace!(addr);
+            s.hex = hex.to_string();
+            s.isbr = isbr;
+            s
+        };
+        // 1000: mov eax, 0x90c3; 1005: jmp 0x1000, then the immediate is
+        // rewritten, then its byte c3 is executed as ret
+        let trace = vec![
+            stmt(0x1000, "B8C3900000", false),
+            stmt(0x1005, "EBF9", true),
+            stmt(0x1000, "B890900000", false),
+            stmt(0x1005, "EBF9", true),
+            stmt(0x1001, "C3", true),
+            stmt(0x1005, "EBF9", true),
+        ];
+        let cfg = Cfg::from_blocks(Bb::new(trace));
+        let keys = vec![Key::new(0x1000, 0), Key::new(0x1000, 1), k(0x1001), k(0x1005)];
+        assert_eq!(cfg.verts.keys().cloned().collect::<Vec<_>>(), keys);
+        assert_eq!(cfg.verts[&k(0x1005)].count, 3);
+        for gen in 0..2 {
+            let e = &cfg.edges[&Key::new(0x1000, gen)][&k(0x1005)];
+            assert_eq!(e.kind, EdgeKind::Fallthrough);
+        }
+        assert!(cfg.edges[&k(0x1005)].contains_key(&Key::new(0x1000, 1)));
+        assert!(cfg.edges[&k(0x1005)].contains_key(&k(0x1001)));
+        assert_eq!(cfg.edges[&k(0x1001)][&k(0x1005)].kind, EdgeKind::Return);
+    }
+
+    #[test]
+    fn kinds() {
+        let instr = |addr, hex: &str, text: &str| Instr::new(addr, hex.to_string(), text.to_string(), true);
+        let jz = instr(0x1000, "7410", "jz 0x1012");
+        assert_eq!(EdgeKind::classify(&jz, 0x1012), EdgeKind::Taken);
+        assert_eq!(EdgeKind::classify(&jz, 0x1002), EdgeKind::Fallthrough);
+        let call = instr(0x1000, "E800000000", "call 0x1005");
+        assert_eq!(EdgeKind::classify(&call, 0x1005), EdgeKind::Call);
+        let call = instr(0x1000, "", "callq  *0x2fe2(%rip)");
+        assert_eq!(EdgeKind::classify(&call, 0x2000), EdgeKind::Indirect);
+        let jmp = instr(0x1000, "", "notrack jmp rax");
+        assert_eq!(EdgeKind::classify(&jmp, 0x2000), EdgeKind::Indirect);
+        assert_eq!(EdgeKind::classify(&instr(0x1000, "EB00", "jmp 0x1002"), 0x1002), EdgeKind::Jump);
+        assert_eq!(EdgeKind::classify(&instr(0x1000, "F3C3", "rep ret"), 0x2000), EdgeKind::Return);
+        assert_eq!(EdgeKind::classify(&instr(0x1000, "90", "nop"), 0x1001), EdgeKind::Fallthrough);
+        // The hexdump wins over the text
+        assert_eq!(EdgeKind::classify(&instr(0x1000, "FFE0", "jmp 0x2000"), 0x2000), EdgeKind::Indirect);
     }
 }
@@ -0,0 +1,369 @@
+//! Dataflow analysis of the registers over the traced graph
+//!
+//! A problem gives the facts, their meet and the transfer through a vertex,
+//! the solver iterates them to the fixed point. Instrs without the hexdump
+//! are not decoded, so they are assumed to use no registers. The foreign code
+//! and the stubs of the calls follow the System V calling convention: they
+//! read the argument registers and clobber the caller-saved ones.
+
+use cfg::{Cfg, Key, NodeBase};
+use disasm::{Access, Reg, DEFAULT_BITNESS};
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+pub trait Problem {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+    /// Fact flowing into the vertices without predecessors, or successors
+    /// if the problem is backward
+    fn boundary(&self) -> Self::Fact;
+    /// Identity of the meet
+    fn top(&self) -> Self::Fact;
+    fn meet(&self, into: &mut Self::Fact, other: &Self::Fact);
+    fn transfer(&self, k: Key, fact: &Self::Fact) -> Self::Fact;
+}
+
+pub struct Solution<F> {
+    /// Facts before the first instr of every vertex
+    pub before: BTreeMap<Key, F>,
+    /// Facts after the last instr of every vertex
+    pub after: BTreeMap<Key, F>,
+}
+
+/// Iterates the transfers of `p` over `cfg` until nothing changes
+pub fn solve<P: Problem>(cfg: &Cfg, p: &P) -> Solution<P::Fact> {
+    let forward = p.direction() == Direction::Forward;
+    let preds = cfg.preds();
+    let (sources, targets) = {
+        let preds = |k: Key| preds.get(&k).cloned().unwrap_or_default();
+        let succs = |k: Key| cfg.succs(k);
+        let all = |f: &dyn Fn(Key) -> BTreeSet<Key>| -> BTreeMap<Key, BTreeSet<Key>> {
+            cfg.verts.keys().map(|&k| (k, f(k))).collect()
+        };
+        if forward {
+            (all(&preds), all(&succs))
+        } else {
+            (all(&succs), all(&preds))
+        }
+    };
+
+    let mut input: BTreeMap<Key, P::Fact> = cfg.verts.keys().map(|&k| (k, p.top())).collect();
+    let mut output = input.clone();
+    let mut work: VecDeque<Key> = if forward {
+        cfg.verts.keys().cloned().collect()
+    } else {
+        cfg.verts.keys().rev().cloned().collect()
+    };
+    let mut queued: BTreeSet<Key> = work.iter().cloned().collect();
+    while let Some(k) = work.pop_front() {
+        queued.remove(&k);
+        let mut fact = if sources[&k].is_empty() { p.boundary() } else { p.top() };
+        for s in sources[&k].iter() {
+            p.meet(&mut fact, &output[s]);
+        }
+        let out = p.transfer(k, &fact);
+        input.insert(k, fact);
+        if out != output[&k] {
+            output.insert(k, out);
+            for &t in targets[&k].iter() {
+                if queued.insert(t) {
+                    work.push_back(t);
+                }
+            }
+        }
+    }
+    if forward {
+        Solution {
+            before: input,
+            after: output,
+        }
+    } else {
+        Solution {
+            before: output,
+            after: input,
+        }
+    }
+}
+
+/// Argument registers of a call, along with the stack pointer
+const ARGS: &[&str] = &["rdi", "rsi", "rdx", "rcx", "r8", "r9", "rsp"];
+/// Registers a call may clobber, the return ones included
+const CLOBBERED: &[&str] = &[
+    "rax", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "flags",
+];
+
+/// Registers used by a call of the code the graph does not show
+fn call() -> Access {
+    let regs = |names: &[&str]| names.iter().filter_map(|x| Reg::parse(x, DEFAULT_BITNESS)).collect();
+    Access {
+        reads: regs(ARGS),
+        writes: regs(CLOBBERED),
+        kills: regs(CLOBBERED),
+    }
+}
+
+/// Decoded instrs of every vertex by the address
+fn accesses(cfg: &Cfg) -> BTreeMap<Key, Vec<(usize, Access)>> {
+    cfg.verts
+        .iter()
+        .map(|(&k, v)| {
+            let instrs = match v.node {
+                NodeBase::Block(ref b) => {
+                    b.instrs
+                        .iter()
+                        .filter_map(|x| x.decoded().map(|d| (x.addr, d.access.clone())))
+                        .collect()
+                }
+                NodeBase::Foreign(ref f) => vec![(f.foreign_addr, call())],
+            };
+            (k, instrs)
+        })
+        .collect()
+}
+
+/// Registers live at the vertices, none is assumed live at the exits
+pub struct Liveness {
+    instrs: BTreeMap<Key, Vec<(usize, Access)>>,
+}
+
+impl Liveness {
+    pub fn new(cfg: &Cfg) -> Liveness {
+        Liveness { instrs: accesses(cfg) }
+    }
+}
+
+impl Problem for Liveness {
+    type Fact = BTreeSet<Reg>;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn boundary(&self) -> BTreeSet<Reg> {
+        BTreeSet::new()
+    }
+
+    fn top(&self) -> BTreeSet<Reg> {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, into: &mut BTreeSet<Reg>, other: &BTreeSet<Reg>) {
+        into.extend(other.iter().cloned());
+    }
+
+    fn transfer(&self, k: Key, fact: &BTreeSet<Reg>) -> BTreeSet<Reg> {
+        let mut live = fact.clone();
+        for (_, a) in self.instrs[&k].iter().rev() {
+            for r in a.kills.iter() {
+                live.remove(r);
+            }
+            live.extend(a.reads.iter().cloned());
+        }
+        live
+    }
+}
+
+/// Register written or read by the instr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Site {
+    pub key: Key,
+    pub addr: usize,
+    pub reg: Reg,
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{:x}", self.reg, self.addr)
+    }
+}
+
+/// Definitions of the registers reaching the vertices
+///
+/// Partial and conditional writes do not kill the earlier definitions.
+pub struct ReachingDefs {
+    instrs: BTreeMap<Key, Vec<(usize, Access)>>,
+}
+
+impl ReachingDefs {
+    pub fn new(cfg: &Cfg) -> ReachingDefs {
+        ReachingDefs { instrs: accesses(cfg) }
+    }
+
+    fn step(defs: &mut BTreeSet<Site>, k: Key, addr: usize, a: &Access) {
+        let killed: Vec<Site> = defs.iter().filter(|d| a.kills.contains(&d.reg)).cloned().collect();
+        for d in killed {
+            defs.remove(&d);
+        }
+        for &r in a.writes.iter() {
+            defs.insert(Site {
+                key: k,
+                addr,
+                reg: r,
+            });
+        }
+    }
+
+    /// Uses of every definition, reached by the solution `sol`
+    pub fn chains(&self, sol: &Solution<BTreeSet<Site>>) -> BTreeMap<Site, BTreeSet<Site>> {
+        let mut res: BTreeMap<Site, BTreeSet<Site>> = BTreeMap::new();
+        for (&k, instrs) in self.instrs.iter() {
+            let mut defs = sol.before[&k].clone();
+            for &(addr, ref a) in instrs.iter() {
+                for d in defs.iter().filter(|d| a.reads.contains(&d.reg)) {
+                    res.entry(*d).or_default().insert(Site {
+                        key: k,
+                        addr,
+                        reg: d.reg,
+                    });
+                }
+                ReachingDefs::step(&mut defs, k, addr, a);
+            }
+        }
+        res
+    }
+}
+
+impl Problem for ReachingDefs {
+    type Fact = BTreeSet<Site>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self) -> BTreeSet<Site> {
+        BTreeSet::new()
+    }
+
+    fn top(&self) -> BTreeSet<Site> {
+        BTreeSet::new()
+    }
+
+    fn meet(&self, into: &mut BTreeSet<Site>, other: &BTreeSet<Site>) {
+        into.extend(other.iter().cloned());
+    }
+
+    fn transfer(&self, k: Key, fact: &BTreeSet<Site>) -> BTreeSet<Site> {
+        let mut defs = fact.clone();
+        for &(addr, ref a) in self.instrs[&k].iter() {
+            ReachingDefs::step(&mut defs, k, addr, a);
+        }
+        defs
+    }
+}
+
+/// Adds the live registers and the reaching definitions to the labels
+pub fn annotate(cfg: &mut Cfg) {
+    let live = solve(cfg, &Liveness::new(cfg));
+    let reaching = solve(cfg, &ReachingDefs::new(cfg));
+    let join = |x: Vec<String>| if x.is_empty() { "-".to_string() } else { x.join(", ") };
+    for &k in live.before.keys() {
+        let notes = cfg.notes.entry(k).or_default();
+        notes.push(format!("live in: {}", join(live.before[&k].iter().map(|x| x.to_string()).collect())));
+        notes.push(format!("live out: {}", join(live.after[&k].iter().map(|x| x.to_string()).collect())));
+        notes.push(format!("reaching: {}", join(reaching.before[&k].iter().map(|x| x.to_string()).collect())));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use dataflow::*;
+    use base::ForeignInfo;
+    use trace::{self, Bb, TraceStmt};
+
+    fn stmt(addr: usize, hex: &str, tid: usize) -> TraceStmt {
+        TraceStmt {
+            isbr: hex.starts_with('7') || hex == "C3",
+            tid: Some(tid),
+            ..trace::test::stmt(addr, hex, "")
+        }
+    }
+
+    fn names<T: ToString>(s: &BTreeSet<T>) -> Vec<String> {
+        s.iter().map(|x| x.to_string()).collect()
+    }
+
+    /// 1: mov eax, 1; test ebx, ebx; je 8
+    /// 8: add eax, ebx (along with 1 as the fallthrough); ret
+    fn diamond() -> Cfg {
+        let taken = vec![
+            stmt(1, "B801000000", 0),
+            stmt(6, "85DB", 0),
+            stmt(8, "7402", 0),
+            stmt(0xc, "01D8", 0),
+            stmt(0xe, "C3", 0),
+        ];
+        let fallthrough = vec![
+            stmt(1, "B801000000", 1),
+            stmt(6, "85DB", 1),
+            stmt(8, "7402", 1),
+            stmt(0xa, "FFC0", 1),
+            stmt(0xc, "01D8", 1),
+            stmt(0xe, "C3", 1),
+        ];
+        Cfg::from_blocks(Bb::new(taken.into_iter().chain(fallthrough)))
+    }
+
+    #[test]
+    fn liveness() {
+        let cfg = diamond();
+        let live = solve(&cfg, &Liveness::new(&cfg));
+        let k = Key::from;
+        assert_eq!(names(&live.before[&k(1)]), vec!["rbx", "rsp"]);
+        assert_eq!(names(&live.after[&k(1)]), vec!["rax", "rbx", "rsp"]);
+        assert_eq!(names(&live.before[&k(0xa)]), vec!["rax", "rbx", "rsp"]);
+        assert_eq!(names(&live.before[&k(0xc)]), vec!["rax", "rbx", "rsp"]);
+        assert!(live.after[&k(0xc)].is_empty());
+    }
+
+    #[test]
+    fn reaching() {
+        let cfg = diamond();
+        let p = ReachingDefs::new(&cfg);
+        let sol = solve(&cfg, &p);
+        let k = Key::from;
+        assert_eq!(names(&sol.after[&k(1)]), vec!["rax@1", "flags@6"]);
+        // inc keeps CF, so the flags of test still reach
+        assert_eq!(names(&sol.before[&k(0xc)]), vec!["rax@1", "flags@6", "rax@a", "flags@a"]);
+        let chains = p.chains(&sol);
+        let def = |addr, r: &str| *chains.keys().find(|d| d.addr == addr && d.reg.to_string() == r).unwrap();
+        assert_eq!(names(&chains[&def(1, "rax")]), vec!["rax@a", "rax@c"]);
+        assert_eq!(names(&chains[&def(0xa, "rax")]), vec!["rax@c"]);
+        assert_eq!(names(&chains[&def(6, "flags")]), vec!["flags@8"]);
+    }
+
+    #[test]
+    fn calls() {
+        // 1: mov edi, 1; mov ecx, 2; call puts; 10: add eax, ecx; ret
+        let mut call = stmt(0xb, "E800000000", 0);
+        call.isbr = true;
+        call.foreign = Some(ForeignInfo {
+            foreign_addr: 0x7f00,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        let trace = vec![
+            stmt(1, "BF01000000", 0),
+            stmt(6, "B902000000", 0),
+            call,
+            stmt(0x10, "01C8", 0),
+            stmt(0x12, "C3", 0),
+        ];
+        let cfg = Cfg::from_blocks(Bb::new(trace));
+        let k = Key::from;
+        let live = solve(&cfg, &Liveness::new(&cfg));
+        assert_eq!(names(&live.before[&k(0x7f00)]), vec!["rcx", "rdx", "rsp", "rsi", "rdi", "r8", "r9"]);
+        assert_eq!(names(&live.before[&k(1)]), vec!["rdx", "rsp", "rsi", "r8", "r9"]);
+        let sol = solve(&cfg, &ReachingDefs::new(&cfg));
+        let reaching = names(&sol.before[&k(0x10)]);
+        assert!(reaching.contains(&"rcx@7f00".to_string()));
+        assert!(!reaching.contains(&"rcx@6".to_string()));
+    }
+}
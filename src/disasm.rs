@@ -0,0 +1,378 @@
+//! x86/x86-64 disassembling of the raw code
+
+extern crate iced_x86;
+
+use self::iced_x86::{Decoder, DecoderOptions, FlowControl, FormatMnemonicOptions, Formatter,
+                     InstructionInfoFactory, Instruction, IntelFormatter, OpAccess, OpKind,
+                     Register, RflagsBits};
+use base::Instr;
+use binary;
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Bitness assumed for the traces which do not tell it
+pub const DEFAULT_BITNESS: u32 = 64;
+
+/// Control-flow class of the instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flow {
+    /// Continues with the next instr
+    Next,
+    Jump,
+    Conditional,
+    IndirectJump,
+    Call,
+    IndirectCall,
+    Return,
+    /// `int`, `syscall` and alike
+    Interrupt,
+    /// `xbegin`, `xabort` and `xend`
+    Transaction,
+    /// `ud2` and alike
+    Exception,
+}
+
+impl Flow {
+    fn new(f: FlowControl) -> Flow {
+        match f {
+            FlowControl::UnconditionalBranch => Flow::Jump,
+            FlowControl::ConditionalBranch => Flow::Conditional,
+            FlowControl::IndirectBranch => Flow::IndirectJump,
+            FlowControl::Call => Flow::Call,
+            FlowControl::IndirectCall => Flow::IndirectCall,
+            FlowControl::Return => Flow::Return,
+            FlowControl::Interrupt => Flow::Interrupt,
+            FlowControl::XbeginXabortXend => Flow::Transaction,
+            FlowControl::Exception => Flow::Exception,
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Instruction decoded from its bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub mnemonic: String,
+    pub operands: Vec<String>,
+    pub flow: Flow,
+    /// Target of the direct near branch
+    pub target: Option<usize>,
+    /// Length in bytes
+    pub len: usize,
+    pub access: Access,
+}
+
+/// Register as a whole, the sub-registers are accounted to the full one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Reg {
+    /// `rax` for `eax`, `ax` and `al` in 64-bit code
+    Full(Register),
+    /// Arithmetic flags
+    Flags,
+}
+
+impl Reg {
+    /// Register named as in the disasm text, `flags` for the flags
+    pub fn parse(name: &str, bitness: u32) -> Option<Reg> {
+        if name == "flags" {
+            return Some(Reg::Flags);
+        }
+        let r = Register::values().find(|r| format!("{:?}", r).eq_ignore_ascii_case(name))?;
+        Some(Reg::Full(if bitness == 64 { r.full_register() } else { r.full_register32() }))
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reg::Full(r) => f.write_str(&format!("{:?}", r).to_lowercase()),
+            Reg::Flags => f.write_str("flags"),
+        }
+    }
+}
+
+/// Registers used by the instruction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Access {
+    pub reads: BTreeSet<Reg>,
+    pub writes: BTreeSet<Reg>,
+    /// Written registers losing the previous value entirely
+    ///
+    /// Partial and conditional writes keep the rest of the old value.
+    pub kills: BTreeSet<Reg>,
+}
+
+const ARITH_FLAGS: u32 = RflagsBits::OF | RflagsBits::SF | RflagsBits::ZF | RflagsBits::AF |
+    RflagsBits::CF | RflagsBits::PF;
+
+/// Registers read and written by the instruction
+fn access(ins: &Instruction, factory: &mut InstructionInfoFactory, bitness: u32) -> Access {
+    let mut res = Access::default();
+    for used in factory.info(ins).used_registers() {
+        let r = used.register();
+        let full = if bitness == 64 { r.full_register() } else { r.full_register32() };
+        // 32-bit writes zero the upper half in 64-bit code
+        let whole = r == full || (bitness == 64 && r.is_gpr32());
+        let reg = Reg::Full(full);
+        match used.access() {
+            OpAccess::Read | OpAccess::CondRead => {
+                res.reads.insert(reg);
+            }
+            OpAccess::Write | OpAccess::ReadWrite => {
+                if used.access() == OpAccess::ReadWrite {
+                    res.reads.insert(reg);
+                }
+                res.writes.insert(reg);
+                if whole {
+                    res.kills.insert(reg);
+                }
+            }
+            OpAccess::CondWrite => {
+                res.writes.insert(reg);
+            }
+            OpAccess::ReadCondWrite => {
+                res.reads.insert(reg);
+                res.writes.insert(reg);
+            }
+            _ => (),
+        }
+    }
+    if ins.rflags_read() & ARITH_FLAGS != 0 {
+        res.reads.insert(Reg::Flags);
+    }
+    let modified = ins.rflags_modified() & ARITH_FLAGS;
+    if modified != 0 {
+        res.writes.insert(Reg::Flags);
+        if modified == ARITH_FLAGS {
+            res.kills.insert(Reg::Flags);
+        }
+    }
+    res
+}
+
+/// Intel syntax formatter printing the text the way Pin does
+fn formatter() -> IntelFormatter {
+    let mut f = IntelFormatter::new();
+    {
+        let opts = f.options_mut();
+        opts.set_space_after_operand_separator(true);
+        opts.set_hex_prefix("0x");
+        opts.set_hex_suffix("");
+        opts.set_uppercase_hex(false);
+        opts.set_branch_leading_zeros(false);
+    }
+    f
+}
+
+/// Prefixes which may precede the mnemonic in the disasm text
+const PREFIXES: &[&str] = &[
+    "bnd", "notrack", "lock", "rep", "repe", "repz", "repne", "repnz",
+];
+
+/// Target of the direct branch, `0x401120` or `401120`
+fn direct_target(operand: &str) -> Option<usize> {
+    let s = operand.trim();
+    usize::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+/// Guesses the control-flow class and the direct target from the disasm text
+///
+/// Both Intel (Pin) and AT&T (QEMU) syntax is understood.
+pub fn parse_flow(text: &str) -> (Flow, Option<usize>) {
+    let mut words = text.split_whitespace().skip_while(|x| PREFIXES.contains(x));
+    let m = words.next().unwrap_or("");
+    let direct = direct_target(&words.collect::<Vec<_>>().join(" "));
+    let flow = if m.starts_with("ret") || m.starts_with("iret") || m.starts_with("lret") {
+        Flow::Return
+    } else if m.starts_with("call") {
+        if direct.is_some() { Flow::Call } else { Flow::IndirectCall }
+    } else if m.starts_with("jmp") {
+        if direct.is_some() { Flow::Jump } else { Flow::IndirectJump }
+    } else if m.starts_with('j') || m.starts_with("loop") {
+        Flow::Conditional
+    } else if m.starts_with("int") || m.starts_with("sys") {
+        Flow::Interrupt
+    } else if m == "ud2" {
+        Flow::Exception
+    } else {
+        Flow::Next
+    };
+    match flow {
+        Flow::Call | Flow::Jump | Flow::Conditional => (flow, direct),
+        _ => (flow, None),
+    }
+}
+
+/// Formatter and the register usage tables kept over the decoded instrs
+struct Context {
+    formatter: IntelFormatter,
+    factory: InstructionInfoFactory,
+    bitness: u32,
+}
+
+impl Context {
+    fn new(bitness: u32) -> Context {
+        Context {
+            formatter: formatter(),
+            factory: InstructionInfoFactory::new(),
+            bitness,
+        }
+    }
+}
+
+thread_local! {
+    /// Context of `decode_one`, rebuilt only when the bitness changes
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+fn decoded(ins: &Instruction, ctx: &mut Context) -> Decoded {
+    let formatter = &mut ctx.formatter;
+    let mut mnemonic = String::new();
+    formatter.format_mnemonic_options(ins, &mut mnemonic, FormatMnemonicOptions::NO_PREFIXES);
+    let operands = (0..formatter.operand_count(ins))
+        .map(|i| {
+            let mut op = String::new();
+            let _ = formatter.format_operand(ins, &mut op, i);
+            op
+        })
+        .collect();
+    let target = match ins.op0_kind() {
+        OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => {
+            Some(ins.near_branch_target() as usize)
+        }
+        _ => None,
+    };
+    Decoded {
+        mnemonic,
+        operands,
+        flow: Flow::new(ins.flow_control()),
+        target,
+        len: ins.len(),
+        access: access(ins, &mut ctx.factory, ctx.bitness),
+    }
+}
+
+/// Decodes the single instruction of `code` placed at `addr`
+pub fn decode_one(code: &[u8], addr: usize, bitness: u32) -> Option<Decoded> {
+    let mut decoder = Decoder::with_ip(bitness, code, addr as u64, DecoderOptions::NONE);
+    let ins = decoder.decode();
+    if ins.is_invalid() {
+        return None;
+    }
+    CONTEXT.with(|x| {
+        let mut ctx = x.borrow_mut();
+        if ctx.as_ref().map(|c| c.bitness) != Some(bitness) {
+            *ctx = Some(Context::new(bitness));
+        }
+        ctx.as_mut().map(|c| decoded(&ins, c))
+    })
+}
+
+/// Decodes the instructions of `code` placed at `addr`
+///
+/// Decoding stops at the end of `code` or at the first invalid instruction.
+pub fn decode(code: &[u8], addr: usize, bitness: u32) -> Vec<Instr> {
+    let mut decoder = Decoder::with_ip(bitness, code, addr as u64, DecoderOptions::NONE);
+    let mut ctx = Context::new(bitness);
+    let mut res = Vec::new();
+    for ins in decoder.iter() {
+        if ins.is_invalid() {
+            break;
+        }
+        let offset = ins.ip() as usize - addr;
+        let mut text = String::new();
+        ctx.formatter.format(&ins, &mut text);
+        res.push(Instr::with_decoded(
+            ins.ip() as usize,
+            binary::encode_hex(&code[offset..offset + ins.len()]),
+            text,
+            ins.flow_control() != FlowControl::Next,
+            decoded(&ins, &mut ctx),
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use disasm::*;
+
+    #[test]
+    fn decoding() {
+        // xor ebp, ebp; call $+5; ret; <invalid>
+        let code = [0x31, 0xed, 0xe8, 0x00, 0x00, 0x00, 0x00, 0xc3, 0x0f, 0x0b, 0x06];
+        let instrs = decode(&code, 0x400000, 64);
+        assert_eq!(instrs.len(), 4);
+        assert_eq!(instrs[0].text, "xor ebp, ebp");
+        assert_eq!(instrs[0].hex, "31ED");
+        assert!(!instrs[0].isbr);
+        assert_eq!(instrs[1].addr, 0x400002);
+        assert_eq!(instrs[1].text, "call 0x400007");
+        assert!(instrs[1].isbr);
+        assert_eq!(instrs[2].text, "ret");
+        assert_eq!(instrs[1].decoded().map(|x| x.target), Some(Some(0x400007)));
+        // ud2 is a branch for the control flow
+        assert!(instrs[3].isbr);
+    }
+
+    #[test]
+    fn classification() {
+        // call 0x400007
+        let d = decode_one(&[0xe8, 0x00, 0x00, 0x00, 0x00], 0x400002, 64).unwrap();
+        assert_eq!(d.mnemonic, "call");
+        assert_eq!(d.operands, vec!["0x400007"]);
+        assert_eq!((d.flow, d.target, d.len), (Flow::Call, Some(0x400007), 5));
+        // rep movsb byte ptr [rdi], byte ptr [rsi]
+        let d = decode_one(&[0xf3, 0xa4], 0, 64).unwrap();
+        assert_eq!(d.mnemonic, "movsb");
+        assert_eq!(d.flow, Flow::Next);
+        // jmp qword ptr [rip+0x10]
+        let d = decode_one(&[0xff, 0x25, 0x10, 0x00, 0x00, 0x00], 0x1000, 64).unwrap();
+        assert_eq!(d.operands, vec!["qword ptr [0x1016]"]);
+        assert_eq!((d.flow, d.target), (Flow::IndirectJump, None));
+        // jne 0x1000 in 32-bit mode
+        let d = decode_one(&[0x75, 0xfe], 0x1000, 32).unwrap();
+        assert_eq!((d.flow, d.target), (Flow::Conditional, Some(0x1000)));
+        assert_eq!(decode_one(&[0x0f], 0, 64), None);
+    }
+
+    #[test]
+    fn registers() {
+        let regs = |v: Vec<Register>| v.into_iter().map(Reg::Full).collect::<BTreeSet<_>>();
+        // add eax, ebx
+        let a = decode_one(&[0x01, 0xd8], 0, 64).unwrap().access;
+        assert_eq!(a.reads, regs(vec![Register::RAX, Register::RBX]));
+        assert_eq!(a.kills, {
+            let mut k = regs(vec![Register::RAX]);
+            k.insert(Reg::Flags);
+            k
+        });
+        // mov al, byte ptr [rsi] keeps the rest of rax
+        let a = decode_one(&[0x8a, 0x06], 0, 64).unwrap().access;
+        assert_eq!(a.reads, regs(vec![Register::RSI]));
+        assert_eq!(a.writes, regs(vec![Register::RAX]));
+        assert!(a.kills.is_empty());
+        // inc ecx leaves CF, jne reads the flags
+        let a = decode_one(&[0xff, 0xc1], 0, 64).unwrap().access;
+        assert!(a.writes.contains(&Reg::Flags) && !a.kills.contains(&Reg::Flags));
+        let a = decode_one(&[0x75, 0xfe], 0, 64).unwrap().access;
+        assert!(a.reads.contains(&Reg::Flags));
+        assert_eq!(Reg::Full(Register::RAX).to_string(), "rax");
+        assert_eq!(Reg::parse("al", 64), Some(Reg::Full(Register::RAX)));
+        assert_eq!(Reg::parse("ax", 32), Some(Reg::Full(Register::EAX)));
+        assert_eq!(Reg::parse("foo", 64), None);
+    }
+
+    #[test]
+    fn text_flow() {
+        assert_eq!(parse_flow("jz 0x1012"), (Flow::Conditional, Some(0x1012)));
+        assert_eq!(parse_flow("callq  0x401120"), (Flow::Call, Some(0x401120)));
+        assert_eq!(parse_flow("callq  *0x2fe2(%rip)"), (Flow::IndirectCall, None));
+        assert_eq!(parse_flow("notrack jmp rax"), (Flow::IndirectJump, None));
+        assert_eq!(parse_flow("rep ret"), (Flow::Return, None));
+        assert_eq!(parse_flow("syscall"), (Flow::Interrupt, None));
+        assert_eq!(parse_flow("xor ebp, ebp"), (Flow::Next, None));
+    }
+}
@@ -0,0 +1,225 @@
+//! Dominators and post-dominators of the traced graph
+//!
+//! Immediate dominators are computed by the iterative algorithm of Cooper,
+//! Harvey and Kennedy. Post-dominators are the dominators of the reversed
+//! graph rooted in a virtual exit, which every exit of the graph leads to.
+
+use cfg::{Cfg, Key};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub struct Dominators {
+    /// Vertices reachable from the root in reverse postorder
+    pub order: Vec<Key>,
+    /// Immediate dominator of every reachable vertex but the roots
+    pub idom: BTreeMap<Key, Key>,
+    /// Dominance frontier of every reachable vertex
+    pub frontiers: BTreeMap<Key, BTreeSet<Key>>,
+}
+
+impl Dominators {
+    /// Dominators of the vertices reachable from `entry`
+    pub fn new(cfg: &Cfg, entry: Key) -> Dominators {
+        Dominators::compute(Some(entry), |x| match x {
+            Some(k) => cfg.succs(k),
+            None => BTreeSet::new(),
+        })
+    }
+
+    /// Post-dominators of the vertices reaching the exits
+    ///
+    /// The vertices immediately post-dominated by the virtual exit are the
+    /// roots of the tree, so it is a forest if there are several exits.
+    pub fn post(cfg: &Cfg) -> Dominators {
+        let preds = cfg.preds();
+        let exits = cfg.exits();
+        Dominators::compute(None, |x| match x {
+            Some(k) => preds.get(&k).cloned().unwrap_or_default(),
+            None => exits.clone(),
+        })
+    }
+
+    /// Whether every path from the root to `b` goes through `a`
+    pub fn dominates(&self, a: Key, b: Key) -> bool {
+        let mut x = b;
+        loop {
+            if x == a {
+                return true;
+            }
+            match self.idom.get(&x) {
+                Some(&d) => x = d,
+                None => return false,
+            }
+        }
+    }
+
+    /// Computes the tree of the graph given by `succs`, `None` is virtual
+    fn compute<F: Fn(Option<Key>) -> BTreeSet<Key>>(root: Option<Key>, succs: F) -> Dominators {
+        // Numbering in reverse postorder, the root is 0
+        let mut post = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![(root, succs(root).into_iter().collect::<Vec<_>>())];
+        seen.extend(root);
+        while let Some(&mut (x, ref mut next)) = stack.last_mut() {
+            match next.pop() {
+                Some(y) => {
+                    if seen.insert(y) {
+                        let next = succs(Some(y)).into_iter().collect();
+                        stack.push((Some(y), next));
+                    }
+                }
+                None => {
+                    post.push(x);
+                    stack.pop();
+                }
+            }
+        }
+        post.reverse();
+        let order = post;
+        let number: HashMap<Option<Key>, usize> = order.iter().enumerate().map(|(i, &x)| (x, i)).collect();
+        let mut preds = vec![Vec::new(); order.len()];
+        for (i, &x) in order.iter().enumerate() {
+            for y in succs(x) {
+                preds[number[&Some(y)]].push(i);
+            }
+        }
+
+        let idom = idoms(&preds);
+        let mut frontiers = vec![BTreeSet::new(); order.len()];
+        for (b, ps) in preds.iter().enumerate() {
+            // The root is in the frontiers of the vertices it is reached from
+            let stop = if b == 0 { None } else { Some(idom[b]) };
+            for &p in ps.iter() {
+                let mut runner = p;
+                while Some(runner) != stop {
+                    frontiers[runner].insert(b);
+                    if runner == 0 {
+                        break;
+                    }
+                    runner = idom[runner];
+                }
+            }
+        }
+
+        let key = |i: usize| order[i];
+        Dominators {
+            order: order.iter().filter_map(|&x| x).collect(),
+            idom: (1..order.len())
+                .filter_map(|i| Some((key(i)?, key(idom[i])?)))
+                .collect(),
+            frontiers: frontiers
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, f)| Some((key(i)?, f.into_iter().filter_map(&key).collect())))
+                .collect(),
+        }
+    }
+}
+
+/// Immediate dominators of the vertices numbered in reverse postorder
+///
+/// The root is 0, it is its own immediate dominator.
+fn idoms(preds: &[Vec<usize>]) -> Vec<usize> {
+    let mut idom = vec![usize::MAX; preds.len()];
+    if preds.is_empty() {
+        return idom;
+    }
+    idom[0] = 0;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in 1..preds.len() {
+            let new = preds[b]
+                .iter()
+                .cloned()
+                .filter(|&p| idom[p] != usize::MAX)
+                .fold(None, |acc, p| match acc {
+                    None => Some(p),
+                    Some(x) => Some(intersect(&idom, p, x)),
+                });
+            if let Some(x) = new {
+                if idom[b] != x {
+                    idom[b] = x;
+                    changed = true;
+                }
+            }
+        }
+    }
+    idom
+}
+
+/// Nearest common dominator of `a` and `b`
+fn intersect(idom: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while a > b {
+            a = idom[a];
+        }
+        while b > a {
+            b = idom[b];
+        }
+    }
+    a
+}
+
+#[cfg(test)]
+mod test {
+    use dom::*;
+    use trace::{Bb, TraceStmt};
+    use trace::test::at;
+
+    fn stmt(addr: usize, tid: Option<usize>) -> TraceStmt {
+        TraceStmt { tid, ..at(addr) }
+    }
+
+    fn path(addrs: &[usize]) -> Cfg {
+        Cfg::from_blocks(Bb::new(addrs.iter().map(|&a| stmt(a, None))))
+    }
+
+    fn keys(v: &[usize]) -> BTreeSet<Key> {
+        v.iter().map(|&x| Key::from(x)).collect()
+    }
+
+    #[test]
+    fn dominators() {
+        // 1 -> 2 -> 4, 1 -> 3 -> 4, 4 -> 1, 4 -> 5
+        let cfg = path(&[1, 2, 4, 1, 3, 4, 5]);
+        let k = Key::from;
+        let dom = Dominators::new(&cfg, cfg.entry().unwrap());
+        assert_eq!(dom.order.len(), 5);
+        assert_eq!(dom.order[0], k(1));
+        let idom: Vec<(usize, usize)> = dom.idom.iter().map(|(a, b)| (a.addr, b.addr)).collect();
+        assert_eq!(idom, vec![(2, 1), (3, 1), (4, 1), (5, 4)]);
+        assert!(dom.dominates(k(1), k(5)));
+        assert!(!dom.dominates(k(2), k(4)));
+        assert_eq!(dom.frontiers[&k(2)], keys(&[4]));
+        assert_eq!(dom.frontiers[&k(4)], keys(&[1]));
+        assert_eq!(dom.frontiers[&k(1)], keys(&[1]));
+        assert!(dom.frontiers[&k(5)].is_empty());
+    }
+
+    #[test]
+    fn post_dominators() {
+        let cfg = path(&[1, 2, 4, 1, 3, 4, 5]);
+        let k = Key::from;
+        let pdom = Dominators::post(&cfg);
+        assert_eq!(pdom.order[0], k(5));
+        let idom: Vec<(usize, usize)> = pdom.idom.iter().map(|(a, b)| (a.addr, b.addr)).collect();
+        assert_eq!(idom, vec![(1, 4), (2, 4), (3, 4), (4, 5)]);
+        assert_eq!(pdom.frontiers[&k(2)], keys(&[1]));
+        assert_eq!(pdom.frontiers[&k(1)], keys(&[4]));
+        assert_eq!(pdom.frontiers[&k(4)], keys(&[4]));
+    }
+
+    #[test]
+    fn several_exits() {
+        // Thread 1 runs 1 -> 2, thread 2 runs 1 -> 3
+        let trace = vec![stmt(1, Some(1)), stmt(1, Some(2)), stmt(2, Some(1)), stmt(3, Some(2))];
+        let cfg = Cfg::from_blocks(Bb::new(trace));
+        let k = Key::from;
+        let pdom = Dominators::post(&cfg);
+        assert_eq!(pdom.order.len(), 3);
+        assert!(pdom.idom.is_empty());
+        assert_eq!(pdom.frontiers[&k(2)], keys(&[1]));
+        assert_eq!(pdom.frontiers[&k(3)], keys(&[1]));
+    }
+}
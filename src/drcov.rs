@@ -0,0 +1,355 @@
+//! Import of DynamoRIO drcov coverage files
+//!
+//! The coverage has no execution order, so it only gives the vertices of the
+//! graph: every covered basic block is rebased against the module table and,
+//! on request, disassembled from the module file.
+
+use base::{Block, Instr};
+use cfg::Cfg;
+use disasm;
+use image::Image;
+use parsing::{ErrorKind, ParseError};
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+pub const MAGIC: &str = "DRCOV VERSION";
+
+/// Module columns of the table without the columns line
+const LEGACY_COLUMNS: &[&str] = &["id", "base", "end", "entry", "path"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub id: usize,
+    /// Module the segment belongs to, the module itself for older versions
+    pub containing: usize,
+    pub start: usize,
+    pub end: usize,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BbEntry {
+    /// Offset from the module start
+    pub start: usize,
+    pub size: usize,
+    pub module: usize,
+}
+
+#[derive(Debug)]
+pub struct Drcov {
+    pub modules: Vec<Module>,
+    pub bbs: Vec<BbEntry>,
+}
+
+/// Reader of the drcov sections with the position tracking
+struct Reader<R> {
+    input: R,
+    line: String,
+    index: usize,
+    offset: usize,
+}
+
+impl<R: BufRead> Reader<R> {
+    fn error(&self, kind: ErrorKind) -> ParseError {
+        ParseError {
+            index: self.index,
+            offset: self.offset,
+            kind,
+        }
+    }
+
+    fn syntax(&self, msg: &str) -> ParseError {
+        self.error(ErrorKind::Syntax(msg.to_string()))
+    }
+
+    fn read_line(&mut self) -> Result<String, ParseError> {
+        self.line.clear();
+        let n = self.input.read_line(&mut self.line).map_err(|e| {
+            ParseError {
+                index: self.index,
+                offset: self.offset,
+                kind: ErrorKind::Io(e.to_string()),
+            }
+        })?;
+        if n == 0 {
+            return Err(self.error(ErrorKind::Truncated));
+        }
+        self.offset += n;
+        Ok(self.line.trim_end().to_string())
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), ParseError> {
+        match self.input.read_exact(buf) {
+            Ok(()) => {
+                self.offset += buf.len();
+                Ok(())
+            }
+            Err(_) => Err(self.error(ErrorKind::Truncated)),
+        }
+    }
+}
+
+fn parse_num(s: &str) -> Option<usize> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Value of `header: value` line
+fn value<'a>(line: &'a str, header: &str) -> Option<&'a str> {
+    line.strip_prefix(header).map(|x| x.trim())
+}
+
+impl Drcov {
+    pub fn read<R: BufRead>(input: R) -> Result<Drcov, ParseError> {
+        let mut r = Reader {
+            input,
+            line: String::new(),
+            index: 0,
+            offset: 0,
+        };
+        let version = value(&r.read_line()?, MAGIC)
+            .and_then(|x| parse_num(x.trim_start_matches(':')))
+            .ok_or_else(|| r.error(ErrorKind::UnknownFormat))?;
+        if !(2..=4).contains(&version) {
+            return Err(r.error(ErrorKind::Version(version)));
+        }
+        // Skip the flavor up to the module table
+        let mut line = r.read_line()?;
+        while value(&line, "Module Table:").is_none() {
+            line = r.read_line()?;
+        }
+        // "Module Table: version 2, count 3" or "Module Table: 3"
+        let count = line.rsplit([' ', ':'])
+            .next()
+            .and_then(parse_num)
+            .ok_or_else(|| r.syntax("wrong module count"))?;
+        // The legacy table has no columns line
+        let legacy = !r.input.fill_buf().map(|x| x.starts_with(b"Columns:")).unwrap_or(false);
+        let columns: Vec<String> = if legacy {
+            LEGACY_COLUMNS.iter().map(|x| x.to_string()).collect()
+        } else {
+            let line = r.read_line()?;
+            value(&line, "Columns:")
+                .ok_or_else(|| r.syntax("no module columns"))?
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .collect()
+        };
+        let column = |names: &[&str]| columns.iter().position(|x| names.contains(&x.as_str()));
+        let id = column(&["id"]).ok_or_else(|| r.syntax("no id column"))?;
+        let start = column(&["base", "start"]).ok_or_else(|| r.syntax("no base column"))?;
+        let end = column(&["end"]).ok_or_else(|| r.syntax("no end column"))?;
+        let containing = column(&["containing_id"]);
+        let path = column(&["path"]).unwrap_or(columns.len() - 1);
+
+        let mut modules = Vec::new();
+        for i in 0..count {
+            r.index = i;
+            let line = r.read_line()?;
+            // Path is the last column and may contain commas
+            let fields: Vec<&str> = line.splitn(columns.len(), ',').map(|x| x.trim()).collect();
+            let num = |i: usize| fields.get(i).and_then(|x| parse_num(x));
+            let m = Module {
+                id: num(id).ok_or_else(|| r.syntax("wrong module id"))?,
+                containing: 0,
+                start: num(start).ok_or_else(|| r.syntax("wrong module base"))?,
+                end: num(end).ok_or_else(|| r.syntax("wrong module end"))?,
+                path: fields.get(path).unwrap_or(&"").to_string(),
+            };
+            let containing = containing.and_then(num).unwrap_or(m.id);
+            modules.push(Module { containing, ..m });
+        }
+
+        let count = {
+            let line = r.read_line()?;
+            value(&line, "BB Table:")
+                .and_then(|x| x.split_whitespace().next())
+                .and_then(parse_num)
+                .ok_or_else(|| r.syntax("no bb table"))?
+        };
+        let mut bbs = Vec::new();
+        let text = r.input.fill_buf().map(|x| x.starts_with(b"module id")).unwrap_or(false);
+        if text {
+            let _ = r.read_line()?;
+        }
+        for i in 0..count {
+            r.index = i;
+            let bb = if text {
+                // module[  4]: 0x0000000000001120,   8
+                let line = r.read_line()?;
+                let open = line.find('[').ok_or_else(|| r.syntax("wrong bb"))?;
+                let close = line.find(']').ok_or_else(|| r.syntax("wrong bb"))?;
+                let colon = line.find(':').ok_or_else(|| r.syntax("wrong bb"))?;
+                let mut rest = line[colon + 1..].split(',');
+                let (start, size) = (rest.next().and_then(parse_num), rest.next().and_then(parse_num));
+                BbEntry {
+                    start: start.ok_or_else(|| r.syntax("wrong bb start"))?,
+                    size: size.ok_or_else(|| r.syntax("wrong bb size"))?,
+                    module: parse_num(&line[open + 1..close]).ok_or_else(|| r.syntax("wrong bb module"))?,
+                }
+            } else {
+                // struct { u32 start; u16 size; u16 mod_id; }
+                let mut buf = [0u8; 8];
+                r.read_bytes(&mut buf)?;
+                BbEntry {
+                    start: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize,
+                    size: u16::from_le_bytes([buf[4], buf[5]]) as usize,
+                    module: u16::from_le_bytes([buf[6], buf[7]]) as usize,
+                }
+            };
+            bbs.push(bb);
+        }
+        Ok(Drcov {
+            modules,
+            bbs,
+        })
+    }
+
+    /// Runtime address of the block
+    pub fn addr(&self, bb: &BbEntry) -> Option<usize> {
+        self.modules.get(bb.module).map(|m| m.start + bb.start)
+    }
+
+    /// Builds the graph of the covered blocks without edges
+    ///
+    /// If `disasm` is set, module files are read from disk to fill the
+    /// instructions, otherwise every block is a single placeholder instr.
+    pub fn to_cfg(&self, disasm: bool) -> Cfg {
+        let mut images: HashMap<usize, Option<Image>> = HashMap::new();
+        let mut cfg = Cfg::new();
+        for bb in self.bbs.iter() {
+            let (m, addr) = match (self.modules.get(bb.module), self.addr(bb)) {
+                (Some(m), Some(addr)) => (m, addr),
+                _ => {
+                    eprintln!("Unknown module {} of bb {:x}. Skipping...", bb.module, bb.start);
+                    continue;
+                }
+            };
+            let instrs = if disasm {
+                let main = self.modules.get(m.containing).unwrap_or(m);
+                let image = images.entry(main.id).or_insert_with(|| {
+                    Image::load(&main.path)
+                        .map_err(|e| eprintln!("Can't load {}: {}", main.path, e))
+                        .ok()
+                });
+                image
+                    .as_ref()
+                    .and_then(|img| {
+                        let code = img.bytes(img.base + addr - main.start)?;
+                        let code = &code[..bb.size.min(code.len())];
+                        Some(disasm::decode(code, addr, img.bitness))
+                    })
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            let instrs = if instrs.is_empty() {
+                vec![Instr::new(addr, String::new(), format!("{} bytes", bb.size), true)]
+            } else {
+                instrs
+            };
+            cfg.insert_block(Block { instrs });
+        }
+        cfg
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drcov::*;
+    use cfg::Key;
+    use image::test::temp_elf;
+
+    fn header(path: &str) -> String {
+        format!(
+            "DRCOV VERSION: 2\n\
+             DRCOV FLAVOR: drcov\n\
+             Module Table: version 2, count 2\n\
+             Columns: id, base, end, entry, checksum, timestamp, path\n  \
+             0, 0x555555554000, 0x555555556000, 0x0000000000000000, 0x00000000, 0x00000000, {}\n  \
+             1, 0x7ffff7dd5000, 0x7ffff7dfc000, 0x0000000000000000, 0x00000000, 0x00000000, /lib/ld.so\n",
+            path
+        )
+    }
+
+    #[test]
+    fn binary_table() {
+        let mut data = header("/bin/true").into_bytes();
+        data.extend_from_slice(b"BB Table: 2 bbs\n");
+        data.extend_from_slice(&[0x20, 0x11, 0, 0, 5, 0, 0, 0]);
+        data.extend_from_slice(&[0x00, 0x10, 0, 0, 3, 0, 1, 0]);
+        let cov = Drcov::read(&data[..]).unwrap();
+        assert_eq!(cov.modules.len(), 2);
+        assert_eq!(cov.modules[1].path, "/lib/ld.so");
+        assert_eq!(cov.bbs[0], BbEntry { start: 0x1120, size: 5, module: 0 });
+        assert_eq!(cov.addr(&cov.bbs[1]), Some(0x7ffff7dd6000));
+
+        let cfg = cov.to_cfg(false);
+        let addrs: Vec<usize> = cfg.verts.keys().map(|x| x.addr).collect();
+        assert_eq!(addrs, vec![0x555555555120, 0x7ffff7dd6000]);
+        assert!(cfg.edges.is_empty());
+
+        // Truncated table
+        let res = Drcov::read(&data[..data.len() - 1]);
+        assert_eq!(res.unwrap_err().kind, ErrorKind::Truncated);
+
+        // Counts are not trusted for allocation
+        let mut data = header("/bin/true").into_bytes();
+        data.extend_from_slice(b"BB Table: 0xffffffffffff bbs\n");
+        let res = Drcov::read(&data[..]);
+        assert_eq!(res.unwrap_err().kind, ErrorKind::Truncated);
+    }
+
+    #[test]
+    fn text_table() {
+        let mut data = header("/bin/true");
+        data.push_str("BB Table: 1 bbs\nmodule id, start, size:\nmodule[  1]: 0x0000000000001000,   3\n");
+        let cov = Drcov::read(data.as_bytes()).unwrap();
+        assert_eq!(cov.bbs, vec![BbEntry { start: 0x1000, size: 3, module: 1 }]);
+    }
+
+    #[test]
+    fn legacy_table() {
+        let data = "DRCOV VERSION: 2\n\
+                    DRCOV FLAVOR: drcov\n\
+                    Module Table: 2\n  \
+                    0, 0x555555554000, 0x555555556000, 0x0000000000000000, /bin/true\n  \
+                    1, 0x7ffff7dd5000, 0x7ffff7dfc000, 0x0000000000000000, /lib/ld, with comma.so\n\
+                    BB Table: 1 bbs\n\
+                    module id, start, size:\n\
+                    module[  1]: 0x0000000000001000,   3\n";
+        let cov = Drcov::read(data.as_bytes()).unwrap();
+        assert_eq!(cov.modules[1].path, "/lib/ld, with comma.so");
+        assert_eq!(cov.modules[1].containing, 1);
+        assert_eq!(cov.addr(&cov.bbs[0]), Some(0x7ffff7dd6000));
+    }
+
+    #[test]
+    fn version() {
+        let res = Drcov::read(&b"DRCOV VERSION: 300\n"[..]);
+        assert_eq!(res.unwrap_err().kind, ErrorKind::Version(300));
+    }
+
+    #[test]
+    fn disassembling() {
+        // xor ebp, ebp; ret
+        let path = temp_elf("drcov", 0x400000, &[0x31, 0xed, 0xc3]);
+        let mut data = header(path.to_str().unwrap()).into_bytes();
+        data.extend_from_slice(b"BB Table: 1 bbs\n");
+        data.extend_from_slice(&[0x00, 0x00, 0, 0, 3, 0, 0, 0]);
+        let cfg = Drcov::read(&data[..]).unwrap().to_cfg(true);
+
+        let node = &cfg.verts[&Key::from(0x555555554000)];
+        match node.node {
+            ::cfg::NodeBase::Block(ref b) => {
+                let text: Vec<&str> = b.instrs.iter().map(|x| x.text.as_str()).collect();
+                assert_eq!(text, vec!["xor ebp, ebp", "ret"]);
+            }
+            _ => panic!("Block expected"),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Recovery of the functions executed in the trace
+//!
+//! Call targets are the function entries. Every block is attributed to the
+//! function on top of the shadow call stack. Calls are shown in the graph of
+//! the caller as stub nodes named after the callee.
+
+use base::{Addressable, ForeignInfo};
+use cfg::{Cfg, Key};
+use disasm::Flow;
+use shadow::{Event, Shadow};
+use trace::Bb;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Name of the function, also the name of the stub nodes of its calls
+pub fn name(entry: usize) -> String {
+    format!("sub_{:x}", entry)
+}
+
+pub struct Function {
+    pub entry: usize,
+    /// Graph of the blocks executed in the function
+    pub cfg: Cfg,
+}
+
+struct Frame {
+    entry: usize,
+    /// Last node of the function path interrupted by the call
+    saved: Option<Key>,
+}
+
+#[derive(Default)]
+struct Thread {
+    /// Frames of the shadow stack
+    stack: Vec<Frame>,
+    /// Block ending with a call, waiting for the callee entry
+    call: Option<Bb>,
+}
+
+pub struct Functions {
+    pub funcs: BTreeMap<usize, Function>,
+    /// Entries of the functions every block is executed in
+    pub owners: BTreeMap<usize, BTreeSet<usize>>,
+    shadow: Shadow,
+    threads: HashMap<Option<usize>, Thread>,
+}
+
+fn cfg(funcs: &mut BTreeMap<usize, Function>, entry: usize) -> &mut Cfg {
+    &mut funcs
+        .entry(entry)
+        .or_insert_with(|| {
+            Function {
+                entry,
+                cfg: Cfg::new(),
+            }
+        })
+        .cfg
+}
+
+/// Starts a new path of the thread in the function
+fn enter(funcs: &mut BTreeMap<usize, Function>, tid: Option<usize>, entry: usize) -> Frame {
+    Frame {
+        entry,
+        saved: cfg(funcs, entry).resume(tid, None),
+    }
+}
+
+/// Resumes the path of the function interrupted by the left frame
+fn leave(funcs: &mut BTreeMap<usize, Function>, tid: Option<usize>, frame: Frame) {
+    cfg(funcs, frame.entry).resume(tid, frame.saved);
+}
+
+impl Functions {
+    pub fn new() -> Functions {
+        Functions {
+            funcs: BTreeMap::new(),
+            owners: BTreeMap::new(),
+            shadow: Shadow::new(),
+            threads: HashMap::new(),
+        }
+    }
+
+    pub fn from_blocks<I: IntoIterator<Item = Bb>>(v: I) -> Functions {
+        let mut funcs = Functions::new();
+        for x in v {
+            funcs.push(x);
+        }
+        funcs.finish();
+        funcs
+    }
+
+    /// Adds the next traced block to the function it is executed in
+    pub fn push(&mut self, bb: Bb) {
+        let addr = match bb.addr() {
+            Some(addr) => addr,
+            None => return,
+        };
+        let tid = bb.tid();
+        let events = self.shadow.push(&bb);
+        let funcs = &mut self.funcs;
+        let t = self.threads.entry(tid).or_default();
+        for e in events {
+            match e {
+                Event::Call { entry, .. } => {
+                    if let Some(mut call) = t.call.take() {
+                        call.stmts.last_mut().unwrap().foreign = Some(ForeignInfo {
+                            foreign_addr: entry,
+                            foreign_name: name(entry),
+                            stub: true,
+                        });
+                        cfg(funcs, t.stack.last().unwrap().entry).push(call);
+                    }
+                    t.stack.push(enter(funcs, tid, entry));
+                }
+                Event::TailCall { entry, .. } => {
+                    leave(funcs, tid, t.stack.pop().unwrap());
+                    t.stack.push(enter(funcs, tid, entry));
+                }
+                Event::Return { .. } => leave(funcs, tid, t.stack.pop().unwrap()),
+                Event::Mismatch { frames, .. } |
+                Event::Longjmp { frames, .. } |
+                Event::Exception { frames, .. } => {
+                    for _ in 0..frames {
+                        leave(funcs, tid, t.stack.pop().unwrap());
+                    }
+                }
+                Event::Foreign { .. } => (),
+            }
+        }
+        // Either the first block of the thread or the one returned to
+        // the caller, which was not traced
+        for f in self.shadow.stack(tid)[t.stack.len()..].iter() {
+            t.stack.push(enter(funcs, tid, f.entry));
+        }
+        let entry = t.stack.last().unwrap().entry;
+        // Call which has not entered a function, like `call $+5`
+        if let Some(call) = t.call.take() {
+            cfg(funcs, entry).push(call);
+        }
+        self.owners.entry(addr).or_default().insert(entry);
+
+        // Foreign code is not traced, so its calls return immediately
+        let flow = if bb.foreign_info().is_some() {
+            None
+        } else {
+            bb.last_instr().map(|x| x.flow().0)
+        };
+        match flow {
+            Some(Flow::Call) | Some(Flow::IndirectCall) => t.call = Some(bb),
+            _ => cfg(funcs, entry).push(bb),
+        }
+    }
+
+    /// Adds the calls the trace ended in and splits the blocks
+    pub fn finish(&mut self) {
+        for (_, t) in self.threads.drain() {
+            if let Some(call) = t.call {
+                cfg(&mut self.funcs, t.stack.last().unwrap().entry).push(call);
+            }
+        }
+        for f in self.funcs.values_mut() {
+            f.cfg.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use func::*;
+    use cfg::{EdgeKind, NodeBase, STUB};
+    use trace::test::stmt;
+
+    #[test]
+    fn calls() {
+        // 1000: call 0x2000; 1005: call 0x2000; 100a: ret; 2000: ret
+        let call = |addr| stmt(addr, "E8FB0F0000", "call 0x2000");
+        let ret = |addr| stmt(addr, "C3", "ret");
+        let mut call2 = call(0x1005);
+        call2.hex = "E8F60F0000".to_string();
+        let trace = vec![call(0x1000), ret(0x2000), call2, ret(0x2000), ret(0x100a)];
+        let funcs = Functions::from_blocks(Bb::new(trace));
+
+        assert_eq!(funcs.funcs.keys().cloned().collect::<Vec<_>>(), vec![0x1000, 0x2000]);
+        let main = &funcs.funcs[&0x1000].cfg;
+        let addrs: Vec<usize> = main.verts.keys().map(|x| x.addr).collect();
+        assert_eq!(addrs, vec![0x1000, 0x1005, 0x100a, 0x2000]);
+        let k = Key::from;
+        let stub = Key::new(0x2000, STUB);
+        match main.verts[&stub].node {
+            NodeBase::Foreign(ref f) => assert_eq!((f.foreign_name.as_str(), f.stub), ("sub_2000", true)),
+            _ => panic!("Stub expected"),
+        }
+        assert_eq!(main.verts[&stub].count, 2);
+        assert_eq!(main.edges[&k(0x1000)][&stub].kind, EdgeKind::Call);
+        assert_eq!(main.edges[&stub][&k(0x1005)].kind, EdgeKind::Return);
+        assert!(main.edges[&stub].contains_key(&k(0x100a)));
+
+        let sub = &funcs.funcs[&0x2000].cfg;
+        assert_eq!(sub.verts.len(), 1);
+        assert_eq!(sub.verts[&k(0x2000)].count, 2);
+        assert!(sub.edges.is_empty());
+        assert_eq!(funcs.owners[&0x1005], vec![0x1000].into_iter().collect());
+        assert_eq!(funcs.owners[&0x2000], vec![0x2000].into_iter().collect());
+    }
+
+    #[test]
+    fn recursion() {
+        // 1000: call 0x2000; 1005: ret; 2000: jz 0x2007; 2002: call 0x2000; 2007: ret
+        let jz = stmt(0x2000, "7405", "jz 0x2007");
+        let call = stmt(0x2002, "E8F9FFFFFF", "call 0x2000");
+        let ret = stmt(0x2007, "C3", "ret");
+        let trace = vec![
+            stmt(0x1000, "E8FB0F0000", "call 0x2000"),
+            jz.clone(),
+            call,
+            jz.clone(),
+            ret.clone(),
+            ret,
+            stmt(0x1005, "C3", "ret"),
+        ];
+        let funcs = Functions::from_blocks(Bb::new(trace));
+        let sub = &funcs.funcs[&0x2000].cfg;
+        let (k, stub) = (Key::from, Key::new(0x2000, STUB));
+        match sub.verts[&k(0x2000)].node {
+            NodeBase::Block(_) => assert_eq!(sub.verts[&k(0x2000)].count, 2),
+            _ => panic!("Entry block expected"),
+        }
+        assert_eq!(sub.edges[&k(0x2002)][&stub].kind, EdgeKind::Call);
+        assert_eq!(sub.edges[&stub][&k(0x2007)].kind, EdgeKind::Return);
+        assert_eq!(sub.edges[&k(0x2000)][&k(0x2007)].kind, EdgeKind::Taken);
+    }
+
+    #[test]
+    fn foreign_calls() {
+        // 1000: call puts; 1005: ret
+        let mut call = stmt(0x1000, "E800000000", "call 0x1005");
+        call.foreign = Some(ForeignInfo {
+            foreign_addr: 0x7f0000001000,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        let trace = vec![call, stmt(0x1005, "C3", "ret")];
+        let funcs = Functions::from_blocks(Bb::new(trace));
+        assert_eq!(funcs.funcs.len(), 1);
+        assert_eq!(funcs.funcs[&0x1000].cfg.verts.len(), 3);
+    }
+
+    #[test]
+    fn plt() {
+        // 1000: call 0x2000; 2000: jmp puts; 1005: ret
+        let mut jmp = stmt(0x2000, "FF25FA2F0000", "jmp qword ptr [rip+0x2ffa]");
+        jmp.foreign = Some(ForeignInfo {
+            foreign_addr: 0x7f0000001000,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        let trace = vec![stmt(0x1000, "E8FB0F0000", "call 0x2000"), jmp, stmt(0x1005, "C3", "ret")];
+        let funcs = Functions::from_blocks(Bb::new(trace));
+        assert_eq!(funcs.owners[&0x1005], vec![0x1000].into_iter().collect());
+        assert_eq!(funcs.funcs[&0x2000].cfg.verts.len(), 2);
+    }
+}
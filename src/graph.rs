@@ -1,20 +1,254 @@
 extern crate dot;
 
-use cfg::{Cfg, NodeBase};
-use base::Addressable;
+use cfg::{Cfg, EdgeKind, Key, NodeBase};
+use func::{self, Functions};
+use callgraph::{self, CallGraph};
+use dom::Dominators;
+use loops::{Loop, Loops};
+use scc::Condensation;
+use regions::{Kind, Region};
 use std::borrow::Cow;
-use std::io::Write;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
 use std::iter;
 
 use itertools::Itertools;
 
-type Node = usize;
-type Edge = (usize, usize);
+type Node = Key;
+type Edge = (Key, Key);
+type CgEdge = (callgraph::Node, callgraph::Node);
+type SccEdge = (usize, usize);
 
 impl Cfg {
     pub fn render_to<W: Write>(&self, out: &mut W) {
         dot::render(self, out).unwrap()
     }
+
+    fn dot_key(&self, n: Node) -> String {
+        match self.verts[&n].node {
+            NodeBase::Block(_) => Cfg::dot_addr(n),
+            NodeBase::Foreign(ref f) => f.foreign_name.clone(),
+        }
+    }
+
+    /// Address of the block, along with the generation of rewritten code
+    fn dot_addr(n: Node) -> String {
+        match n.gen {
+            0 => format!("{:016x}", n.addr),
+            gen => format!("{:016x}.{}", n.addr, gen),
+        }
+    }
+
+    fn dot_label(&self, n: Node) -> String {
+        let v = &self.verts[&n];
+        match v.node {
+            NodeBase::Block(ref b) => {
+                let mut s = Cfg::dot_addr(n);
+                if v.count > 0 {
+                    s.push_str(&format!(" x{}", v.count));
+                }
+                s.push('\n');
+                s.push_str(&b.instrs
+                    .iter()
+                    .map(|x| if self.marks.contains(&x.addr) {
+                        format!("> {}", x.text)
+                    } else {
+                        x.text.clone()
+                    })
+                    .join("\n"));
+                for note in self.notes.get(&n).into_iter().flat_map(|x| x.iter()) {
+                    s.push('\n');
+                    s.push_str(note);
+                }
+                s
+            }
+            NodeBase::Foreign(ref f) => format!("{}\n", f.foreign_name),
+        }
+    }
+
+    /// Whether some instr of the vertex is highlighted
+    fn marked(&self, n: Node) -> bool {
+        match self.verts[&n].node {
+            NodeBase::Block(ref b) => b.instrs.iter().any(|x| self.marks.contains(&x.addr)),
+            NodeBase::Foreign(_) => false,
+        }
+    }
+
+    fn dot_edge_label(&self, e: &Edge) -> String {
+        let &(s, t) = e;
+        let e = &self.edges[&s][&t];
+        format!("{} {}", e.kind, e.count)
+    }
+
+    fn dot_edge_style(&self, e: &Edge) -> dot::Style {
+        let &(s, t) = e;
+        match self.edges[&s][&t].kind {
+            EdgeKind::Fallthrough => dot::Style::Dashed,
+            EdgeKind::Call => dot::Style::Bold,
+            EdgeKind::Return | EdgeKind::Foreign => dot::Style::Dotted,
+            _ => dot::Style::None,
+        }
+    }
+
+    /// Writes the node statement for the subgraphs written by hand
+    fn write_node<W: Write>(&self, out: &mut W, indent: &str, id: &str, n: Node) -> io::Result<()> {
+        writeln!(out, "{}{}[label={}];", indent, id, quote(&self.dot_label(n)))
+    }
+
+    fn write_edge<W: Write>(&self, out: &mut W, indent: &str, ids: (&str, &str), e: &Edge) -> io::Result<()> {
+        write!(out, "{}{} -> {}[label={}", indent, ids.0, ids.1, quote(&self.dot_edge_label(e)))?;
+        let style = self.dot_edge_style(e);
+        if style != dot::Style::None {
+            write!(out, ",style=\"{}\"", style.as_slice())?;
+        }
+        writeln!(out, "];")
+    }
+}
+
+/// Quoted DOT string
+fn quote(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+impl Functions {
+    /// Renders every function as a cluster of the single graph
+    ///
+    /// The dot crate knows nothing about subgraphs, so it is written by hand.
+    pub fn render_to<W: Write>(&self, out: &mut W) {
+        self.write_clusters(out).unwrap()
+    }
+
+    fn write_clusters<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph a {{")?;
+        for (&entry, f) in self.funcs.iter() {
+            let name = func::name(entry);
+            let id = |n| quote(&format!("{}:{}", name, f.cfg.dot_key(n)));
+            writeln!(out, "    subgraph {} {{", quote(&format!("cluster_{}", name)))?;
+            writeln!(out, "        label={};", quote(&name))?;
+            for &n in f.cfg.verts.keys() {
+                f.cfg.write_node(out, "        ", &id(n), n)?;
+            }
+            for (&s, ts) in f.cfg.edges.iter() {
+                for &t in ts.keys() {
+                    f.cfg.write_edge(out, "        ", (&id(s), &id(t)), &(s, t))?;
+                }
+            }
+            writeln!(out, "    }}")?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+impl Dominators {
+    /// Renders the tree with the nodes labelled as the vertices of `cfg`
+    pub fn render_to<W: Write>(&self, cfg: &Cfg, out: &mut W) {
+        self.write_tree(cfg, out).unwrap()
+    }
+
+    fn write_tree<W: Write>(&self, cfg: &Cfg, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph dom {{")?;
+        for &n in self.order.iter() {
+            writeln!(out, "    {}[label={}];", quote(&cfg.dot_key(n)), quote(&cfg.dot_label(n)))?;
+        }
+        for (&n, &d) in self.idom.iter() {
+            writeln!(out, "    {} -> {};", quote(&cfg.dot_key(d)), quote(&cfg.dot_key(n)))?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+impl Loops {
+    /// Renders the graph with every loop as a cluster nested in the enclosing one
+    pub fn render_to<W: Write>(&self, cfg: &Cfg, out: &mut W) {
+        self.write_clusters(cfg, out).unwrap()
+    }
+
+    fn write_clusters<W: Write>(&self, cfg: &Cfg, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph a {{")?;
+        for &n in cfg.verts.keys() {
+            if !self.loops.values().any(|l| l.body.contains(&n)) {
+                cfg.write_node(out, "    ", &quote(&cfg.dot_key(n)), n)?;
+            }
+        }
+        for l in self.loops.values().filter(|l| l.parent.is_none()) {
+            self.write_loop(cfg, out, l, 1)?;
+        }
+        for (&s, ts) in cfg.edges.iter() {
+            for &t in ts.keys() {
+                cfg.write_edge(out, "    ", (&quote(&cfg.dot_key(s)), &quote(&cfg.dot_key(t))), &(s, t))?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_loop<W: Write>(&self, cfg: &Cfg, out: &mut W, l: &Loop, depth: usize) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        writeln!(out, "{}subgraph {} {{", indent, quote(&format!("cluster_loop_{}", l.header)))?;
+        let mut label = format!("loop {}", l.header);
+        if let Some((min, avg, max)) = l.stats() {
+            label.push_str(&format!(" x{} iterations {}/{:.1}/{}", l.iterations.len(), min, avg, max));
+        }
+        writeln!(out, "{}    label={};", indent, quote(&label))?;
+        let nested: Vec<&Loop> = self.loops.values().filter(|x| x.parent == Some(l.header)).collect();
+        for &n in l.body.iter() {
+            if !nested.iter().any(|x| x.body.contains(&n)) {
+                cfg.write_node(out, &format!("{}    ", indent), &quote(&cfg.dot_key(n)), n)?;
+            }
+        }
+        for x in nested {
+            self.write_loop(cfg, out, x, depth + 1)?;
+        }
+        writeln!(out, "{}}}", indent)
+    }
+}
+
+impl Region {
+    /// Renders the graph with every region but the blocks as a nested cluster
+    pub fn render_to<W: Write>(&self, cfg: &Cfg, out: &mut W) {
+        self.write_graph(cfg, out).unwrap()
+    }
+
+    fn write_graph<W: Write>(&self, cfg: &Cfg, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph a {{")?;
+        let mut count = 0;
+        self.write_cluster(cfg, out, 1, &mut count)?;
+        // Vertices unreachable from the entry are not in the regions
+        let blocks: BTreeSet<Key> = self.blocks().into_iter().collect();
+        for &n in cfg.verts.keys().filter(|n| !blocks.contains(n)) {
+            cfg.write_node(out, "    ", &quote(&cfg.dot_key(n)), n)?;
+        }
+        for (&s, ts) in cfg.edges.iter() {
+            for &t in ts.keys() {
+                cfg.write_edge(out, "    ", (&quote(&cfg.dot_key(s)), &quote(&cfg.dot_key(t))), &(s, t))?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    /// Writes the region, `count` numbers the clusters
+    fn write_cluster<W: Write>(&self, cfg: &Cfg, out: &mut W, depth: usize, count: &mut usize) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        if self.kind == Kind::Block {
+            return cfg.write_node(out, &indent, &quote(&cfg.dot_key(self.entry)), self.entry);
+        }
+        writeln!(out, "{}subgraph cluster_{} {{", indent, count)?;
+        writeln!(out, "{}    label={};", indent, quote(&self.kind.to_string()))?;
+        *count += 1;
+        for x in self.children.iter() {
+            x.write_cluster(cfg, out, depth + 1, count)?;
+        }
+        writeln!(out, "{}}}", indent)
+    }
 }
 
 impl<'a> dot::Labeller<'a, Node, Edge> for Cfg {
@@ -23,40 +257,45 @@ impl<'a> dot::Labeller<'a, Node, Edge> for Cfg {
     }
 
     fn node_id(&'a self, n: &Node) -> dot::Id<'a> {
-        let ref v = self.verts[n];
-        dot::Id::new(match v.node {
-            NodeBase::Block(ref b) => 
-                format!("\"{:016x}\"", b.addr().unwrap()),
-            NodeBase::Foreign(ref f) => 
-                format!("\"{}\"", f.foreign_name),
-        }).unwrap()
+        dot::Id::new(format!("\"{}\"", self.dot_key(*n))).unwrap()
     }
 
     fn node_label<'b>(&'b self, n: &Node) -> dot::LabelText<'b> {
-        let ref v = self.verts[n];
-        let s = match v.node {
-            NodeBase::Block(ref b) => {
-                let mut s = format!("{:016x}\n", b.addr().unwrap());
-                s.push_str(&b.instrs.iter().map(|ref x| &x.text).join("\n"));
-                s
-            }
-            NodeBase::Foreign(ref f) => format!("{}\n", f.foreign_name),
-        };
-        dot::LabelText::LabelStr(Cow::Owned(s))
+        dot::LabelText::LabelStr(Cow::Owned(self.dot_label(*n)))
+    }
+
+    fn node_style(&'a self, n: &Node) -> dot::Style {
+        if self.marked(*n) { dot::Style::Bold } else { dot::Style::None }
+    }
+
+    fn node_color(&'a self, n: &Node) -> Option<dot::LabelText<'a>> {
+        if self.marked(*n) {
+            Some(dot::LabelText::LabelStr(Cow::Borrowed("red")))
+        } else {
+            None
+        }
+    }
+
+    fn edge_label<'b>(&'b self, e: &Edge) -> dot::LabelText<'b> {
+        dot::LabelText::LabelStr(Cow::Owned(self.dot_edge_label(e)))
+    }
+
+    fn edge_style(&'a self, e: &Edge) -> dot::Style {
+        self.dot_edge_style(e)
     }
 }
 
 impl<'a> dot::GraphWalk<'a, Node, Edge> for Cfg {
-    fn nodes(&self) -> dot::Nodes<'a, usize> {
-        Cow::Owned(self.verts.iter().map(|(x, _)| *x).collect())
+    fn nodes(&self) -> dot::Nodes<'a, Node> {
+        Cow::Owned(self.verts.keys().copied().collect())
     }
 
     fn edges(&'a self) -> dot::Edges<'a, Edge> {
         Cow::Owned(
             self.edges
                 .iter()
-                .flat_map(|(x, ref y)| {
-                    iter::once(x).cartesian_product(y.iter().cloned())
+                .flat_map(|(x, y)| {
+                    iter::once(x).cartesian_product(y.keys().cloned())
                 })
                 .map(|(&x, y)| (x, y))
                 .collect(),
@@ -73,3 +312,151 @@ impl<'a> dot::GraphWalk<'a, Node, Edge> for Cfg {
         t
     }
 }
+
+impl CallGraph {
+    pub fn render_to<W: Write>(&self, out: &mut W) {
+        dot::render(self, out).unwrap()
+    }
+
+    fn dot_name(n: &callgraph::Node) -> String {
+        match *n {
+            callgraph::Node::Function(entry) => func::name(entry),
+            callgraph::Node::Foreign(ref name) => name.clone(),
+        }
+    }
+}
+
+impl<'a> dot::Labeller<'a, callgraph::Node, CgEdge> for CallGraph {
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        dot::Id::new("calls").unwrap()
+    }
+
+    fn node_id(&'a self, n: &callgraph::Node) -> dot::Id<'a> {
+        dot::Id::new(format!("\"{}\"", CallGraph::dot_name(n))).unwrap()
+    }
+
+    fn node_label<'b>(&'b self, n: &callgraph::Node) -> dot::LabelText<'b> {
+        dot::LabelText::LabelStr(Cow::Owned(CallGraph::dot_name(n)))
+    }
+
+    fn edge_label<'b>(&'b self, e: &CgEdge) -> dot::LabelText<'b> {
+        let call = &self.calls[e];
+        let sites = call.sites.iter().map(|x| format!("{:x}", x)).join(", ");
+        dot::LabelText::LabelStr(Cow::Owned(format!("{} x{}", sites, call.count)))
+    }
+
+    fn edge_style(&'a self, e: &CgEdge) -> dot::Style {
+        if self.calls[e].indirect {
+            dot::Style::Dashed
+        } else {
+            dot::Style::None
+        }
+    }
+}
+
+impl<'a> dot::GraphWalk<'a, callgraph::Node, CgEdge> for CallGraph {
+    fn nodes(&'a self) -> dot::Nodes<'a, callgraph::Node> {
+        Cow::Owned(self.nodes.iter().cloned().collect())
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, CgEdge> {
+        Cow::Owned(self.calls.keys().cloned().collect())
+    }
+
+    fn source(&self, e: &CgEdge) -> callgraph::Node {
+        e.0.clone()
+    }
+
+    fn target(&self, e: &CgEdge) -> callgraph::Node {
+        e.1.clone()
+    }
+}
+
+impl Condensation {
+    pub fn render_to<W: Write>(&self, out: &mut W) {
+        dot::render(self, out).unwrap()
+    }
+
+    /// Renders the blocks of the component `i` along with the neighbouring components
+    pub fn render_component_to<W: Write>(&self, cfg: &Cfg, i: usize, out: &mut W) {
+        self.write_component(cfg, i, out).unwrap()
+    }
+
+    fn write_component<W: Write>(&self, cfg: &Cfg, i: usize, out: &mut W) -> io::Result<()> {
+        let id = |n: Key| match self.owner[&n] {
+            x if x == i => quote(&cfg.dot_key(n)),
+            x => quote(&Condensation::dot_name(x)),
+        };
+        writeln!(out, "digraph {} {{", quote(&Condensation::dot_name(i)))?;
+        for &n in self.comps[i].verts.iter() {
+            cfg.write_node(out, "    ", &id(n), n)?;
+        }
+        let mut neighbours = BTreeSet::new();
+        for (&s, ts) in cfg.edges.iter() {
+            for &t in ts.keys() {
+                let (a, b) = (self.owner[&s], self.owner[&t]);
+                if a == i || b == i {
+                    neighbours.insert(a);
+                    neighbours.insert(b);
+                    cfg.write_edge(out, "    ", (&id(s), &id(t)), &(s, t))?;
+                }
+            }
+        }
+        for x in neighbours.into_iter().filter(|&x| x != i) {
+            writeln!(out, "    {}[label={},shape=box];", quote(&Condensation::dot_name(x)), quote(&self.dot_label(x)))?;
+        }
+        writeln!(out, "}}")
+    }
+
+    fn dot_name(i: usize) -> String {
+        format!("scc_{}", i)
+    }
+
+    fn dot_label(&self, i: usize) -> String {
+        let c = &self.comps[i];
+        let entries = c.entries.iter().map(|x| x.to_string()).join(", ");
+        format!(
+            "{}\n{} blocks, {} instrs\nentries {}",
+            Condensation::dot_name(i),
+            c.verts.len(),
+            c.instrs,
+            entries
+        )
+    }
+}
+
+impl<'a> dot::Labeller<'a, usize, SccEdge> for Condensation {
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        dot::Id::new("scc").unwrap()
+    }
+
+    fn node_id(&'a self, n: &usize) -> dot::Id<'a> {
+        dot::Id::new(Condensation::dot_name(*n)).unwrap()
+    }
+
+    fn node_label<'b>(&'b self, n: &usize) -> dot::LabelText<'b> {
+        dot::LabelText::LabelStr(Cow::Owned(self.dot_label(*n)))
+    }
+
+    fn edge_label<'b>(&'b self, e: &SccEdge) -> dot::LabelText<'b> {
+        dot::LabelText::LabelStr(Cow::Owned(self.edges[e].to_string()))
+    }
+}
+
+impl<'a> dot::GraphWalk<'a, usize, SccEdge> for Condensation {
+    fn nodes(&'a self) -> dot::Nodes<'a, usize> {
+        Cow::Owned((0..self.comps.len()).collect())
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, SccEdge> {
+        Cow::Owned(self.edges.keys().cloned().collect())
+    }
+
+    fn source(&self, e: &SccEdge) -> usize {
+        e.0
+    }
+
+    fn target(&self, e: &SccEdge) -> usize {
+        e.1
+    }
+}
@@ -0,0 +1,125 @@
+//! Executable images loaded from disk
+
+extern crate goblin;
+
+use self::goblin::elf::Elf;
+use self::goblin::elf::program_header::PT_LOAD;
+
+use std::fs::File;
+use std::io::{self, Read};
+
+const PAGE: usize = 0x1000;
+
+struct Segment {
+    vaddr: usize,
+    data: Vec<u8>,
+}
+
+/// Loadable segments of an ELF file
+pub struct Image {
+    /// Link-time address of the first mapped page
+    pub base: usize,
+    /// 32 or 64
+    pub bitness: u32,
+    segments: Vec<Segment>,
+}
+
+impl Image {
+    pub fn load(path: &str) -> io::Result<Image> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Image::parse(path, &data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(path: &str, data: &[u8]) -> Result<Image, String> {
+        let elf = Elf::parse(data).map_err(|e| format!("{}: {}", path, e))?;
+        let mut segments = Vec::new();
+        for ph in elf.program_headers.iter().filter(|x| x.p_type == PT_LOAD) {
+            let (off, size) = (ph.p_offset as usize, ph.p_filesz as usize);
+            let bytes = off.checked_add(size).and_then(|end| data.get(off..end)).ok_or_else(|| {
+                format!("{}: segment is out of file", path)
+            })?;
+            segments.push(Segment {
+                vaddr: ph.p_vaddr as usize,
+                data: bytes.to_vec(),
+            });
+        }
+        let base = segments.iter().map(|x| x.vaddr).min().ok_or_else(|| {
+            format!("{}: no loadable segments", path)
+        })?;
+        Ok(Image {
+            base: base & !(PAGE - 1),
+            bitness: if elf.is_64 { 64 } else { 32 },
+            segments,
+        })
+    }
+
+    /// Bytes from the link-time address up to the end of its segment
+    pub fn bytes(&self, vaddr: usize) -> Option<&[u8]> {
+        self.segments
+            .iter()
+            .find(|x| x.vaddr <= vaddr && vaddr < x.vaddr + x.data.len())
+            .map(|x| &x.data[vaddr - x.vaddr..])
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use image::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Writes `tiny_elf` to a temp file unique to the test and the process
+    pub fn temp_elf(name: &str, vaddr: usize, code: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("trace-anal-{}-{}.elf", name, process::id()));
+        File::create(&path).unwrap().write_all(&tiny_elf(vaddr, code)).unwrap();
+        path
+    }
+
+    /// Builds an x86-64 ELF with a single segment holding `code` at `vaddr`
+    pub fn tiny_elf(vaddr: usize, code: &[u8]) -> Vec<u8> {
+        let (ehsize, phsize) = (64u16, 56u16);
+        let off = (ehsize + phsize) as u64;
+        let mut v = Vec::new();
+        v.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        v.extend_from_slice(&[0; 8]);
+        v.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        v.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+        v.extend_from_slice(&1u32.to_le_bytes());
+        v.extend_from_slice(&(vaddr as u64).to_le_bytes()); // entry
+        v.extend_from_slice(&(ehsize as u64).to_le_bytes()); // phoff
+        v.extend_from_slice(&0u64.to_le_bytes()); // shoff
+        v.extend_from_slice(&0u32.to_le_bytes());
+        v.extend_from_slice(&ehsize.to_le_bytes());
+        v.extend_from_slice(&phsize.to_le_bytes());
+        v.extend_from_slice(&1u16.to_le_bytes()); // phnum
+        v.extend_from_slice(&[0; 6]); // no sections
+        v.extend_from_slice(&PT_LOAD.to_le_bytes());
+        v.extend_from_slice(&5u32.to_le_bytes()); // R+X
+        v.extend_from_slice(&off.to_le_bytes());
+        v.extend_from_slice(&(vaddr as u64).to_le_bytes());
+        v.extend_from_slice(&(vaddr as u64).to_le_bytes());
+        v.extend_from_slice(&(code.len() as u64).to_le_bytes());
+        v.extend_from_slice(&(code.len() as u64).to_le_bytes());
+        v.extend_from_slice(&(PAGE as u64).to_le_bytes());
+        v.extend_from_slice(code);
+        v
+    }
+
+    #[test]
+    fn segments() {
+        let img = Image::parse("tiny", &tiny_elf(0x401000, &[0x90, 0xc3])).unwrap();
+        assert_eq!(img.base, 0x401000);
+        assert_eq!(img.bitness, 64);
+        assert_eq!(img.bytes(0x401001), Some(&[0xc3u8][..]));
+        assert_eq!(img.bytes(0x401002), None);
+
+        // Segment offset near the end of the address space
+        let mut data = tiny_elf(0x401000, &[0x90, 0xc3]);
+        data[72..80].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        assert!(Image::parse("tiny", &data).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! Opening of the trace input
+//!
+//! Compressed inputs are detected by the magic bytes and unpacked on the fly,
+//! so the trace readers always get the plain data.
+
+extern crate flate2;
+extern crate xz2;
+extern crate zstd;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+
+pub const STDIN: &str = "-";
+
+const GZIP: &[u8] = b"\x1f\x8b";
+const ZSTD: &[u8] = b"\x28\xb5\x2f\xfd";
+const XZ: &[u8] = b"\xfd7zXZ\x00";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    fn detect(head: &[u8]) -> Compression {
+        if head.starts_with(GZIP) {
+            Compression::Gzip
+        } else if head.starts_with(ZSTD) {
+            Compression::Zstd
+        } else if head.starts_with(XZ) {
+            Compression::Xz
+        } else {
+            Compression::Plain
+        }
+    }
+}
+
+/// Wraps the input with the decompressor matching its first bytes
+pub fn decompress<R: Read + 'static>(mut input: R) -> io::Result<Box<dyn BufRead>> {
+    // Read the head explicitly, a pipe may hand it over in pieces
+    let mut head = Vec::with_capacity(XZ.len());
+    (&mut input).take(XZ.len() as u64).read_to_end(&mut head)?;
+    let compression = Compression::detect(&head);
+    let input = Cursor::new(head).chain(input);
+
+    Ok(match compression {
+        Compression::Plain => Box::new(BufReader::new(input)),
+        Compression::Gzip => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(input))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::new(input)?)),
+        Compression::Xz => Box::new(BufReader::new(xz2::read::XzDecoder::new_multi_decoder(input))),
+    })
+}
+
+/// Opens the trace file, possibly compressed
+///
+/// `-` stands for stdin. Named pipes are read as they are written to, so
+/// the trace may be consumed while the tracer is still running.
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead>> {
+    if path == STDIN {
+        decompress(io::stdin())
+    } else {
+        decompress(File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use input::*;
+    use input::flate2::write::GzEncoder;
+    use std::io::Write;
+
+    const DATA: &[u8] = br#"[{ "address": 1, "hexDump": "90", "text": "nop" }]"#;
+
+    fn unpack(packed: Vec<u8>) -> Vec<u8> {
+        let mut res = Vec::new();
+        decompress(Cursor::new(packed))
+            .unwrap()
+            .read_to_end(&mut res)
+            .unwrap();
+        res
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(unpack(DATA.to_vec()), DATA);
+        // Shorter than any magic
+        assert_eq!(unpack(b"[]".to_vec()), b"[]");
+    }
+
+    #[test]
+    fn gzip() {
+        let mut enc = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(DATA).unwrap();
+        assert_eq!(unpack(enc.finish().unwrap()), DATA);
+    }
+
+    #[test]
+    fn zstd() {
+        let packed = zstd::stream::encode_all(DATA, 0).unwrap();
+        assert_eq!(unpack(packed), DATA);
+    }
+
+    #[test]
+    fn xz() {
+        let mut enc = xz2::write::XzEncoder::new(Vec::new(), 6);
+        enc.write_all(DATA).unwrap();
+        assert_eq!(unpack(enc.finish().unwrap()), DATA);
+    }
+}
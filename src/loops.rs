@@ -0,0 +1,198 @@
+//! Natural loops of the traced graph
+//!
+//! A back edge goes to a vertex dominating its source, the loop header. The
+//! body is the header along with the vertices reaching the back edges not
+//! through the header. Loops sharing the header are merged.
+
+use cfg::{Cfg, Key};
+use dom::Dominators;
+use trace::Bb;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub struct Loop {
+    pub header: Key,
+    /// Sources of the back edges
+    pub latches: BTreeSet<Key>,
+    /// Vertices of the loop along with the header and the nested loops
+    pub body: BTreeSet<Key>,
+    /// Edges leaving the body
+    pub exits: BTreeSet<(Key, Key)>,
+    /// Header of the innermost enclosing loop
+    pub parent: Option<Key>,
+    /// Number of the enclosing loops, this one included
+    pub depth: usize,
+    /// Iterations of every entry of the loop in the trace
+    pub iterations: Vec<usize>,
+}
+
+impl Loop {
+    fn new(header: Key) -> Loop {
+        Loop {
+            header,
+            latches: BTreeSet::new(),
+            body: BTreeSet::new(),
+            exits: BTreeSet::new(),
+            parent: None,
+            depth: 1,
+            iterations: Vec::new(),
+        }
+    }
+
+    /// Minimum, average and maximum number of iterations per entry
+    pub fn stats(&self) -> Option<(usize, f64, usize)> {
+        let min = *self.iterations.iter().min()?;
+        let max = *self.iterations.iter().max()?;
+        let sum: usize = self.iterations.iter().sum();
+        Some((min, sum as f64 / self.iterations.len() as f64, max))
+    }
+}
+
+pub struct Loops {
+    /// Loops by the header
+    pub loops: BTreeMap<Key, Loop>,
+}
+
+impl Loops {
+    /// Finds the loops among the vertices dominated by `dom` root
+    pub fn new(cfg: &Cfg, dom: &Dominators) -> Loops {
+        let preds = cfg.preds();
+        let mut loops = BTreeMap::new();
+        for &s in dom.order.iter() {
+            for h in cfg.succs(s).into_iter().filter(|&h| dom.dominates(h, s)) {
+                let l = loops.entry(h).or_insert_with(|| Loop::new(h));
+                l.latches.insert(s);
+                l.body.insert(h);
+                let mut stack = vec![s];
+                while let Some(x) = stack.pop() {
+                    if dom.dominates(h, x) && l.body.insert(x) {
+                        stack.extend(preds.get(&x).into_iter().flat_map(|p| p.iter().cloned()));
+                    }
+                }
+            }
+        }
+        for l in loops.values_mut() {
+            for &x in l.body.iter() {
+                for t in cfg.succs(x) {
+                    if !l.body.contains(&t) {
+                        l.exits.insert((x, t));
+                    }
+                }
+            }
+        }
+
+        // The innermost enclosing loop is the smallest one
+        let parents: Vec<(Key, Option<Key>)> = loops
+            .values()
+            .map(|l| {
+                let parent = loops
+                    .values()
+                    .filter(|p| p.header != l.header && p.body.contains(&l.header))
+                    .min_by_key(|p| p.body.len())
+                    .map(|p| p.header);
+                (l.header, parent)
+            })
+            .collect();
+        for &(h, parent) in parents.iter() {
+            let mut depth = 1;
+            let mut p = parent;
+            while let Some(x) = p {
+                depth += 1;
+                p = parents.iter().find(|y| y.0 == x).unwrap().1;
+            }
+            let l = loops.get_mut(&h).unwrap();
+            l.parent = parent;
+            l.depth = depth;
+        }
+        Loops { loops }
+    }
+
+    /// Accounts the iterations of the loops executed by the traced blocks
+    ///
+    /// A loop is entered at its header from outside of the body and iterates
+    /// every time the header is executed again, until the thread leaves it.
+    pub fn count<I: IntoIterator<Item = Bb>>(&mut self, cfg: &Cfg, bbs: I) {
+        // Iterations of the loops being executed by every thread
+        let mut running: HashMap<Option<usize>, BTreeMap<Key, usize>> = HashMap::new();
+        for bb in bbs {
+            let r = running.entry(bb.tid()).or_default();
+            for k in cfg.walk(&bb) {
+                let left: Vec<Key> = r.keys()
+                    .filter(|h| !self.loops[h].body.contains(&k))
+                    .cloned()
+                    .collect();
+                for h in left {
+                    let n = r.remove(&h).unwrap();
+                    self.loops.get_mut(&h).unwrap().iterations.push(n);
+                }
+                if self.loops.contains_key(&k) {
+                    *r.entry(k).or_insert(0) += 1;
+                }
+            }
+        }
+        for (_, r) in running {
+            for (h, n) in r {
+                self.loops.get_mut(&h).unwrap().iterations.push(n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use loops::*;
+    use trace::test::at;
+
+    #[test]
+    fn nested() {
+        // 1 -> 2 -> 3 -> 2 (inner), 3 -> 4 -> 1 (outer), 4 -> 5
+        let mut path = vec![1];
+        for &inner in [3, 1].iter() {
+            for _ in 0..inner {
+                path.extend(vec![2, 3]);
+            }
+            path.extend(vec![4, 1]);
+        }
+        path.extend(vec![2, 3, 4, 5]);
+        let bbs: Vec<Bb> = Bb::new(path.into_iter().map(at)).collect();
+        let cfg = Cfg::from_blocks(bbs.iter().cloned());
+        let dom = Dominators::new(&cfg, cfg.entry().unwrap());
+        let mut loops = Loops::new(&cfg, &dom);
+        loops.count(&cfg, bbs);
+
+        let k = Key::from;
+        assert_eq!(loops.loops.len(), 2);
+        let outer = &loops.loops[&k(1)];
+        assert_eq!(outer.body, vec![k(1), k(2), k(3), k(4)].into_iter().collect());
+        assert_eq!(outer.latches, vec![k(4)].into_iter().collect());
+        assert_eq!(outer.exits, vec![(k(4), k(5))].into_iter().collect());
+        assert_eq!((outer.parent, outer.depth), (None, 1));
+        assert_eq!(outer.iterations, vec![3]);
+
+        let inner = &loops.loops[&k(2)];
+        assert_eq!(inner.body, vec![k(2), k(3)].into_iter().collect());
+        assert_eq!(inner.exits, vec![(k(3), k(4))].into_iter().collect());
+        assert_eq!((inner.parent, inner.depth), (Some(k(1)), 2));
+        assert_eq!(inner.iterations, vec![3, 1, 1]);
+        let (min, avg, max) = inner.stats().unwrap();
+        assert_eq!((min, max), (1, 3));
+        assert!((avg - 5.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn split_header() {
+        // 1: {1, 2}, then jumps into 2, which is the loop header
+        let trace = vec![at(0), {
+            let mut s = at(1);
+            s.isbr = false;
+            s
+        }, at(2), at(2), at(2), at(3)];
+        let bbs: Vec<Bb> = Bb::new(trace).collect();
+        let cfg = Cfg::from_blocks(bbs.iter().cloned());
+        let dom = Dominators::new(&cfg, cfg.entry().unwrap());
+        let mut loops = Loops::new(&cfg, &dom);
+        loops.count(&cfg, bbs);
+        let l = &loops.loops[&Key::from(2)];
+        assert_eq!(l.iterations, vec![3]);
+    }
+}
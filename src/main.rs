@@ -2,51 +2,594 @@ extern crate itertools;
 
 #[macro_use]
 mod parsing;
+use parsing::{Mode, ParseError, Trace};
 mod cfg;
-use cfg::Cfg;
+use cfg::{Cfg, Key};
 mod trace;
-use trace::Bb;
+use trace::{Bb, TraceStmt};
+use base::Addressable;
 mod graph;
 mod base;
+mod binary;
+mod input;
+mod qemu;
+mod image;
+use image::Image;
+mod disasm;
+mod drcov;
+use drcov::Drcov;
+mod recover;
+use recover::Recover;
+mod shadow;
+use shadow::Shadow;
+mod func;
+use func::Functions;
+mod callgraph;
+use callgraph::CallGraph;
+mod rop;
+use rop::{Detector, Suspicious};
+mod version;
+use version::Versions;
+mod dom;
+use dom::Dominators;
+mod loops;
+use loops::Loops;
+mod scc;
+use scc::Condensation;
+mod regions;
+use regions::Region;
+mod cdg;
+use cdg::Cdg;
+mod dataflow;
+use dataflow::{Liveness, ReachingDefs};
+mod slice;
+use slice::Location;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs::File;
-use std::io::{Read, stdout};
+use std::io::{BufRead, BufReader, BufWriter, Write, stdout};
 use std::fmt;
+use std::process;
 
 impl fmt::Display for Cfg {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut prnt = format!("Cfg ###\nVetices: {{");
+        let mut prnt = "Cfg ###\nVetices: {".to_string();
         for (&k, _) in self.verts.iter() {
-            prnt.push_str(&format!(" addr: {}\n", k));
+            prnt.push_str(&format!(" addr: {:?}\n", k));
         }
-        prnt.push_str(&format!("}}\nEdges: {{"));
+        prnt.push_str("}\nEdges: {");
         for (&l, ref r) in self.edges.iter() {
-            prnt.push_str(&format!(" l: {:16}, r: {:?}\n", l, r))
+            prnt.push_str(&format!(" l: {:?}, r: {:?}\n", l, r))
         }
-        prnt.push_str(&format!("}}\n###"));
+        prnt.push_str("}\n###");
         f.write_str(&prnt)
     }
 }
 
-fn main() {
-    let usage = format!(
-        "Use {} <json-file> [<output-dotfile>]",
-        env::args().nth(0).unwrap()
-    );
-    let file = env::args().nth(1).expect(&usage);
-
-    let mut content = String::new();
-    File::open(&file)
-        .expect(&format!("Can't open {}", &file))
-        .read_to_string(&mut content)
-        .expect("Something happend during file reading");
-    let cfg = Cfg::from_blocks(Bb::new(parsing::parse_trace(&content)));
+fn fail(file: &str, e: ParseError) -> ! {
+    eprintln!("Error in {}: {}", file, e);
+    process::exit(1);
+}
+
+fn open_trace(file: &str, mode: Mode) -> Trace<Box<dyn BufRead>> {
+    let input = input::open(file).unwrap_or_else(|_| panic!("Can't open {}", file));
+    Trace::new(input, mode)
+}
+
+/// Converts binary trace to json, any other one to binary
+///
+/// Address-only statements are recovered on the way if there are images.
+fn convert(from: &str, to: &str, mode: Mode, recover: &mut Recover) {
+    let mut out = BufWriter::new(File::create(to).unwrap_or_else(|_| panic!("Can't create {}", to)));
+    let mut next = |stmt: Result<TraceStmt, ParseError>| {
+        let mut stmt = stmt.unwrap_or_else(|e| fail(from, e));
+        if !recover.is_empty() {
+            recover.fill(&mut stmt);
+        }
+        stmt
+    };
+    match open_trace(from, mode) {
+        trace @ Trace::Binary(_) => parsing::write_json(&mut out, trace.map(next)).unwrap(),
+        trace => {
+            let mut writer = binary::Writer::new(out).unwrap();
+            for stmt in trace {
+                writer.write(&next(stmt)).unwrap();
+            }
+            writer.into_inner().flush().unwrap();
+        }
+    }
+}
+
+/// Passes the blocks of the trace to `f`, fails on the trace error
+fn with_stmts<T, F>(file: &str, mode: Mode, recover: &mut Recover, f: F) -> T
+where
+    F: FnOnce(&mut dyn Iterator<Item = TraceStmt>) -> T,
+{
+    let mut error = None;
+    let res = {
+        let mut trace = open_trace(file, mode)
+            .scan(&mut error, |error, x| x.map_err(|e| **error = Some(e)).ok())
+            .map(|mut x| {
+                if !recover.is_empty() {
+                    recover.fill(&mut x);
+                }
+                x
+            });
+        f(&mut trace)
+    };
+    if let Some(e) = error {
+        fail(file, e);
+    }
+    res
+}
+
+fn with_blocks<T, F>(file: &str, mode: Mode, recover: &mut Recover, f: F) -> T
+where
+    F: FnOnce(&mut dyn Iterator<Item = Bb>) -> T,
+{
+    with_stmts(file, mode, recover, |stmts| f(&mut Bb::new(stmts)))
+}
+
+/// Renders the call graph of the trace
+fn callgraph(file: &str, out: Option<&String>, mode: Mode, recover: &mut Recover) {
+    let funcs = with_blocks(file, mode, recover, |bbs| Functions::from_blocks(bbs));
+    let cg = CallGraph::new(&funcs);
+    for (site, targets) in cg.indirect.iter() {
+        eprintln!("indirect {:x}: {:?}", site, targets);
+    }
+    match out {
+        Some(fname) => cg.render_to(&mut File::create(fname).unwrap_or_else(|_| panic!("Can't create {}", fname))),
+        None => cg.render_to(&mut stdout()),
+    }
+}
+
+/// Prints the shadow call stack at every block along with the stack events
+fn stack(file: &str, mode: Mode, recover: &mut Recover) {
+    let out = stdout();
+    let mut out = out.lock();
+    let mut shadow = Shadow::new();
+    with_blocks(file, mode, recover, |bbs| for bb in bbs {
+        for e in shadow.push(&bb) {
+            writeln!(out, "  {}", e).unwrap();
+        }
+        let stack: Vec<String> = shadow.stack(bb.tid()).iter().map(|x| func::name(x.entry)).collect();
+        writeln!(out, "{}\t{:x}\t{}", bb.index, bb.addr().unwrap(), stack.join(" > ")).unwrap();
+    });
+}
+
+/// Renders the graph with the loops as clusters, prints their iterations
+fn loops(file: &str, out: Option<&String>, mode: Mode, recover: &mut Recover) {
+    let cfg = with_blocks(file, mode, recover, |bbs| Cfg::from_blocks(bbs));
+    let entry = match cfg.entry() {
+        Some(x) => x,
+        None => return,
+    };
+    let mut loops = Loops::new(&cfg, &Dominators::new(&cfg, entry));
+    // The iterations are counted over the trace read once more
+    if file == input::STDIN {
+        eprintln!("Warning: stdin can't be read twice, the iterations are not counted");
+    } else {
+        with_blocks(file, mode, recover, |bbs| loops.count(&cfg, bbs));
+    }
+    for l in loops.loops.values() {
+        let exits: Vec<String> = l.exits.iter().map(|&(s, t)| format!("{} -> {}", s, t)).collect();
+        let (min, avg, max) = l.stats().unwrap_or((0, 0.0, 0));
+        eprintln!(
+            "loop {}\tdepth {}\tblocks {}\tentries {}\titerations {}/{:.1}/{}\texits {}",
+            l.header,
+            l.depth,
+            l.body.len(),
+            l.iterations.len(),
+            min,
+            avg,
+            max,
+            exits.join(", ")
+        );
+    }
+    match out {
+        Some(fname) => loops.render_to(&cfg, &mut File::create(fname).unwrap_or_else(|_| panic!("Can't create {}", fname))),
+        None => loops.render_to(&cfg, &mut stdout()),
+    }
+}
+
+/// Renders the graph of the components, or the blocks of the one `comp`
+fn scc(file: &str, out: Option<&String>, comp: Option<usize>, mode: Mode, recover: &mut Recover) {
+    let cfg = with_blocks(file, mode, recover, |bbs| Cfg::from_blocks(bbs));
+    let cond = Condensation::new(&cfg);
+    let mut out: Box<dyn Write> = match out {
+        Some(fname) => Box::new(File::create(fname).unwrap_or_else(|_| panic!("Can't create {}", fname))),
+        None => Box::new(stdout()),
+    };
+    match comp {
+        Some(i) if i < cond.comps.len() => cond.render_component_to(&cfg, i, &mut out),
+        Some(i) => eprintln!("No component {}, there are {}", i, cond.comps.len()),
+        None => cond.render_to(&mut out),
+    }
+}
+
+/// Prints the regions of every function as pseudo-code
+///
+/// If there is `prefix`, the function graphs with the regions as clusters are
+/// written to `<prefix><entry>.dot` as well.
+fn regions(file: &str, prefix: Option<&String>, mode: Mode, recover: &mut Recover) {
+    let funcs = with_blocks(file, mode, recover, |bbs| Functions::from_blocks(bbs));
+    let out = stdout();
+    let mut out = out.lock();
+    for f in funcs.funcs.values() {
+        let entry = match f.cfg.entry() {
+            Some(x) => x,
+            None => continue,
+        };
+        let r = Region::new(&f.cfg, entry);
+        writeln!(out, "{}:", func::name(f.entry)).unwrap();
+        r.write_code(&f.cfg, &mut out, 1).unwrap();
+        if let Some(prefix) = prefix {
+            let fname = format!("{}{:x}.dot", prefix, f.entry);
+            r.render_to(&f.cfg, &mut File::create(&fname).unwrap_or_else(|_| panic!("Can't create {}", fname)));
+        }
+    }
+}
+
+/// Prints the control dependences of every function
+///
+/// If there is `block`, only the branches deciding whether it executes are
+/// printed, the direct ones and the ones they depend on with the distance.
+fn cdg(file: &str, block: Option<usize>, mode: Mode, recover: &mut Recover) {
+    let funcs = with_blocks(file, mode, recover, |bbs| Functions::from_blocks(bbs));
+    let out = stdout();
+    let mut out = out.lock();
+    for f in funcs.funcs.values() {
+        let cdg = Cdg::new(&f.cfg, &Dominators::post(&f.cfg));
+        let kinds = |b: Key, x: Key| -> String {
+            let kinds: Vec<String> = cdg.edges[&(b, x)]
+                .iter()
+                .map(|s| f.cfg.edges[&b][s].kind.to_string())
+                .collect();
+            kinds.join(", ")
+        };
+        let addr = match block {
+            Some(addr) => addr,
+            None => {
+                writeln!(out, "{}:", func::name(f.entry)).unwrap();
+                let branches: BTreeSet<Key> = cdg.edges.keys().map(|e| e.0).collect();
+                for b in branches {
+                    for x in cdg.dependents(b) {
+                        writeln!(out, "  {}\tdecides {}\t{}", b, x, kinds(b, x)).unwrap();
+                    }
+                }
+                continue;
+            }
+        };
+        let verts: Vec<Key> = f.cfg.verts.keys().filter(|k| k.addr == addr).cloned().collect();
+        if verts.is_empty() {
+            continue;
+        }
+        writeln!(out, "{}:", func::name(f.entry)).unwrap();
+        for x in verts {
+            let deciders = cdg.deciders(x);
+            if deciders.is_empty() {
+                writeln!(out, "  {}\talways executed", x).unwrap();
+            }
+            for (b, distance) in deciders {
+                let via = cdg.edges.get(&(b, x)).map_or(String::new(), |_| kinds(b, x));
+                writeln!(out, "  {}\tdecided by {}\tdistance {}\t{}", x, b, distance, via).unwrap();
+            }
+        }
+    }
+}
+
+/// Prints the live registers, the reaching definitions and their uses
+///
+/// If there is `block`, only the vertices at the address are printed.
+fn dataflow(file: &str, block: Option<usize>, mode: Mode, recover: &mut Recover) {
+    let funcs = with_blocks(file, mode, recover, |bbs| Functions::from_blocks(bbs));
+    let out = stdout();
+    let mut out = out.lock();
+    let join = |x: Vec<String>| if x.is_empty() { "-".to_string() } else { x.join(", ") };
+    for f in funcs.funcs.values() {
+        let verts: Vec<Key> = f.cfg.verts.keys().filter(|k| block.map_or(true, |x| k.addr == x)).cloned().collect();
+        if verts.is_empty() {
+            continue;
+        }
+        let live = dataflow::solve(&f.cfg, &Liveness::new(&f.cfg));
+        let defs = ReachingDefs::new(&f.cfg);
+        let reaching = dataflow::solve(&f.cfg, &defs);
+        let chains = defs.chains(&reaching);
+        writeln!(out, "{}:", func::name(f.entry)).unwrap();
+        for k in verts {
+            let show = |x: &BTreeSet<_>| join(x.iter().map(|x: &dataflow::Site| x.to_string()).collect());
+            writeln!(
+                out,
+                "  {}\tlive in {}\tlive out {}\treaching {}",
+                k,
+                join(live.before[&k].iter().map(|x| x.to_string()).collect()),
+                join(live.after[&k].iter().map(|x| x.to_string()).collect()),
+                show(&reaching.before[&k])
+            ).unwrap();
+            for (d, uses) in chains.iter().filter(|x| x.0.key == k) {
+                writeln!(out, "  {}\tdef {}\tused by {}", k, d, show(uses)).unwrap();
+            }
+        }
+    }
+}
+
+/// Prints the immediate dominator, post-dominator and the frontier of every block
+fn dominators(file: &str, mode: Mode, recover: &mut Recover) {
+    let cfg = with_blocks(file, mode, recover, |bbs| Cfg::from_blocks(bbs));
+    let entry = match cfg.entry() {
+        Some(x) => x,
+        None => return,
+    };
+    let (dom, pdom) = (Dominators::new(&cfg, entry), Dominators::post(&cfg));
+    let show = |x: Option<&Key>| x.map_or("-".to_string(), |x| x.to_string());
+    let out = stdout();
+    let mut out = out.lock();
+    for n in dom.order.iter() {
+        let frontier: Vec<String> = dom.frontiers[n].iter().map(|x| x.to_string()).collect();
+        writeln!(
+            out,
+            "{}\tidom {}\tipdom {}\tfrontier {}",
+            n,
+            show(dom.idom.get(n)),
+            show(pdom.idom.get(n)),
+            frontier.join(", ")
+        ).unwrap();
+    }
+}
+
+/// Prints the statements contributing to `loc` at the trace `index`
+///
+/// The statement itself is marked with `>`. Only the trace up to it is read.
+/// If there is `out`, the graph of it with the sliced instrs highlighted is
+/// written there. The instrs are highlighted by address, so are all their
+/// executions and not only the sliced ones.
+fn slice(file: &str, index: usize, loc: Location, out: Option<&String>, mode: Mode, recover: &mut Recover) {
+    let stmts: Vec<TraceStmt> = with_stmts(file, mode, recover, |stmts| stmts.take(index + 1).collect());
+    if index >= stmts.len() {
+        eprintln!("No statement {}, the trace is {} long", index, stmts.len());
+        process::exit(1);
+    }
+    let mut sliced = slice::slice(&stmts, index, loc);
+    sliced.insert(index);
+    {
+        let out = stdout();
+        let mut out = out.lock();
+        for &i in sliced.iter() {
+            let mark = if i == index { '>' } else { ' ' };
+            writeln!(out, "{} {}\t{:x}\t{}", mark, i, stmts[i].addr, stmts[i].text).unwrap();
+        }
+    }
+    if let Some(fname) = out {
+        let mut cfg = Cfg::from_blocks(Bb::new(stmts.iter().cloned()));
+        cfg.marks = sliced.iter().map(|&i| stmts[i].addr).collect();
+        cfg.render_to(&mut File::create(fname).unwrap_or_else(|_| panic!("Can't create {}", fname)));
+    }
+}
+
+/// Prints the suspicious transitions with the surrounding blocks
+fn rop(file: &str, mode: Mode, recover: &mut Recover) {
+    let out = stdout();
+    let mut out = out.lock();
+    let mut print = |s: Suspicious| {
+        writeln!(out, "{}", s).unwrap();
+        for bb in s.context.iter() {
+            let mark = if bb.index == s.index { '>' } else { ' ' };
+            let text: Vec<&str> = bb.stmts.iter().map(|x| x.text.as_str()).collect();
+            writeln!(out, "{} {}\t{:x}\t{}", mark, bb.index, bb.addr().unwrap(), text.join("; ")).unwrap();
+        }
+    };
+    let mut detector = Detector::new(rop::CONTEXT);
+    with_blocks(file, mode, recover, |bbs| for bb in bbs {
+        for s in detector.push(bb) {
+            print(s);
+        }
+    });
+    for s in detector.finish() {
+        print(s);
+    }
+}
+
+/// Prints the rewritten code and the instrs overlapping the other ones
+fn rewrites(file: &str, mode: Mode, recover: &mut Recover) {
+    let mut versions = Versions::new();
+    with_stmts(file, mode, recover, |stmts| for (i, s) in stmts.enumerate() {
+        versions.push(i, &s);
+    });
+    let out = stdout();
+    let mut out = out.lock();
+    for r in versions.records {
+        writeln!(out, "{}", r).unwrap();
+    }
+}
+
+/// Flags of the graph of the whole trace
+#[derive(Default)]
+struct Options {
+    per_thread: Option<String>,
+    per_function: Option<String>,
+    clusters: Option<String>,
+    dom_tree: Option<String>,
+    annotate: bool,
+    disasm: bool,
+}
+
+/// Renders the graph of the whole trace along with the ones the options ask for
+fn graph(file: &str, out: Option<&String>, opts: Options, mode: Mode, recover: &mut Recover) {
+    let mut input = input::open(file).unwrap_or_else(|_| panic!("Can't open {}", file));
+    let coverage = input
+        .fill_buf()
+        .map(|x| x.starts_with(drcov::MAGIC.as_bytes()))
+        .unwrap_or(false);
+    let mut funcs = if opts.per_function.is_some() || opts.clusters.is_some() {
+        Some(Functions::new())
+    } else {
+        None
+    };
+    let (mut cfg, threads) = if coverage {
+        let cov = Drcov::read(input).unwrap_or_else(|e| fail(file, e));
+        funcs = None;
+        (cov.to_cfg(opts.disasm), BTreeMap::new())
+    } else {
+        let mut error = None;
+        let trace = Trace::new(input, mode)
+            .scan(&mut error, |error, x| x.map_err(|e| **error = Some(e)).ok())
+            .map(|mut x| {
+                if !recover.is_empty() {
+                    recover.fill(&mut x);
+                }
+                x
+            });
+        let bbs = Bb::new(trace).inspect(|x| if let Some(ref mut f) = funcs {
+            f.push(x.clone());
+        });
+        let res = if opts.per_thread.is_some() {
+            Cfg::from_blocks_per_thread(bbs)
+        } else {
+            (Cfg::from_blocks(bbs), BTreeMap::new())
+        };
+        if let Some(e) = error {
+            fail(file, e);
+        }
+        res
+    };
     eprintln!("{}", cfg);
+    if opts.annotate {
+        dataflow::annotate(&mut cfg);
+    }
+
+    for (tid, t) in threads {
+        let tid = tid.map_or("unknown".to_string(), |x| x.to_string());
+        let fname = format!("{}{}.dot", opts.per_thread.as_ref().unwrap(), tid);
+        t.render_to(&mut File::create(&fname).unwrap_or_else(|_| panic!("Can't create {}", fname)));
+    }
 
-    if let Some(fname) = env::args().nth(2) {
+    if let Some(mut funcs) = funcs {
+        funcs.finish();
+        if let Some(prefix) = opts.per_function {
+            for f in funcs.funcs.values_mut() {
+                if opts.annotate {
+                    dataflow::annotate(&mut f.cfg);
+                }
+                let fname = format!("{}{:x}.dot", prefix, f.entry);
+                f.cfg.render_to(&mut File::create(&fname).unwrap_or_else(|_| panic!("Can't create {}", fname)));
+            }
+        }
+        if let Some(fname) = opts.clusters {
+            funcs.render_to(&mut File::create(&fname).unwrap_or_else(|_| panic!("Can't create {}", fname)));
+        }
+    }
+
+    if let (Some(fname), Some(entry)) = (opts.dom_tree, cfg.entry()) {
+        let dom = Dominators::new(&cfg, entry);
+        dom.render_to(&cfg, &mut File::create(&fname).unwrap_or_else(|_| panic!("Can't create {}", fname)));
+    }
+
+    if let Some(fname) = out {
         cfg.render_to(&mut File::create(fname).unwrap());
     } else {
         cfg.render_to(&mut stdout());
     }
 }
+
+fn exit_usage(usage: &str) -> ! {
+    eprintln!("{}", usage);
+    process::exit(1);
+}
+
+fn main() {
+    let usage = format!(
+        "Use {0} [--lenient] [--per-thread=<prefix>] [<trace-file>|-] [<output-dotfile>]\n \
+         or {0} [--lenient] convert <trace-file>|- <output-file>\n\
+         or {0} [--lenient] callgraph [<trace-file>|- [<output-dotfile>]]\n\
+         or {0} [--lenient] loops [<trace-file>|- [<output-dotfile>]]\n\
+         or {0} [--lenient] scc [<trace-file>|- [<output-dotfile> [<component>]]]\n\
+         or {0} [--lenient] regions [<trace-file>|- [<output-prefix>]]\n\
+         or {0} [--lenient] stack [<trace-file>|-]\n\
+         or {0} [--lenient] rop [<trace-file>|-]\n\
+         or {0} [--lenient] rewrites [<trace-file>|-]\n\
+         or {0} [--lenient] dominators [<trace-file>|-]\n\
+         or {0} [--lenient] cdg [<trace-file>|- [<block-address>]]\n\
+         or {0} [--lenient] dataflow [<trace-file>|- [<block-address>]]\n\
+         or {0} [--lenient] slice <trace-file>|- <index> <register>|[<address>[:<size>]] [<output-dotfile>]\n\
+         Trace is read from stdin if the file is `-` or omitted, a file named\n\
+         like a subcommand is given by the path, e.g. `./loops`\n\
+         --per-thread additionally writes a graph per thread to <prefix><tid>.dot\n\
+         --functions additionally writes a graph per function to <prefix><entry>.dot\n\
+         --clusters additionally writes all the functions as clusters to <file>\n\
+         --dominators additionally writes the dominator tree from the first block to <file>\n\
+         --dataflow adds the live registers and the reaching definitions to the labels\n\
+         --disasm fills drcov blocks by disassembling the module files\n\
+         --binary=<elf>[@<base>] and --maps=<proc-maps> give the images to\n\
+         recover instrs of address-only traces",
+        env::args().next().unwrap()
+    );
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|x| x.starts_with("--"));
+    let mut mode = Mode::Strict;
+    let mut opts = Options::default();
+    let mut recover = Recover::new();
+    for flag in flags {
+        let mut opt = flag.splitn(2, '=');
+        match (opt.next().unwrap(), opt.next()) {
+            ("--lenient", None) => mode = Mode::Lenient,
+            ("--per-thread", Some(prefix)) => opts.per_thread = Some(prefix.to_string()),
+            ("--functions", Some(prefix)) => opts.per_function = Some(prefix.to_string()),
+            ("--clusters", Some(file)) => opts.clusters = Some(file.to_string()),
+            ("--dominators", Some(file)) => opts.dom_tree = Some(file.to_string()),
+            ("--dataflow", None) => opts.annotate = true,
+            ("--disasm", None) => opts.disasm = true,
+            ("--binary", Some(elf)) => {
+                let mut elf = elf.splitn(2, '@');
+                let path = elf.next().unwrap();
+                let base = elf.next().map(|x| {
+                    usize::from_str_radix(x.trim_start_matches("0x"), 16).unwrap_or_else(|_| exit_usage(&usage))
+                });
+                let image = Image::load(path).unwrap_or_else(|_| panic!("Can't load {}", path));
+                recover.add(image, base);
+            }
+            ("--maps", Some(maps)) => {
+                let maps = File::open(maps).unwrap_or_else(|_| panic!("Can't open {}", maps));
+                recover.add_maps(BufReader::new(maps)).unwrap();
+            }
+            _ => {
+                eprintln!("Unknown option {}", flag);
+                exit_usage(&usage);
+            }
+        }
+    }
+    // Trace file of the subcommand, stdin if omitted
+    let file = |i: usize| args.get(i).map_or(input::STDIN, |x| x.as_str());
+    let address = |x: &String| {
+        usize::from_str_radix(x.trim_start_matches("0x"), 16).unwrap_or_else(|_| exit_usage(&usage))
+    };
+
+    match args.first().map(|x| x.as_str()) {
+        Some("convert") => match (args.get(1), args.get(2)) {
+            (Some(from), Some(to)) => convert(from, to, mode, &mut recover),
+            _ => exit_usage(&usage),
+        },
+        Some("callgraph") => callgraph(file(1), args.get(2), mode, &mut recover),
+        Some("loops") => loops(file(1), args.get(2), mode, &mut recover),
+        Some("regions") => regions(file(1), args.get(2), mode, &mut recover),
+        Some("scc") => {
+            let comp = args.get(3).map(|x| x.parse().unwrap_or_else(|_| exit_usage(&usage)));
+            scc(file(1), args.get(2), comp, mode, &mut recover)
+        }
+        Some("slice") => {
+            let index = args.get(2).and_then(|x| x.parse().ok());
+            let loc = args.get(3).and_then(|x| Location::parse(x));
+            match (index, loc) {
+                (Some(index), Some(loc)) => slice(file(1), index, loc, args.get(4), mode, &mut recover),
+                _ => exit_usage(&usage),
+            }
+        }
+        Some("cdg") => cdg(file(1), args.get(2).map(address), mode, &mut recover),
+        Some("dataflow") => dataflow(file(1), args.get(2).map(address), mode, &mut recover),
+        Some("stack") => stack(file(1), mode, &mut recover),
+        Some("rop") => rop(file(1), mode, &mut recover),
+        Some("rewrites") => rewrites(file(1), mode, &mut recover),
+        Some("dominators") => dominators(file(1), mode, &mut recover),
+        _ => graph(file(0), args.get(1), opts, mode, &mut recover),
+    }
+}
@@ -1,102 +1,542 @@
 pub use base::ForeignInfo;
-pub use trace::TraceStmt;
+pub use trace::{MemAccess, TraceStmt};
 
 extern crate simple_json;
 use self::simple_json::Json;
 use self::simple_json::Number::Unsigned;
 
-use std::collections::HashMap;
+use base::Lazy;
+use binary;
+use qemu::{self, QemuReader};
 
-macro_rules! get(
-    ($e:expr) => (match $e { Some(e) => e, None => return None })
-);
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
 
 macro_rules! parse {
     ($e:expr, $name:expr, $opt:expr, $en:ident :: $t:ident) => (
-        if let Some(& $en::$t(ref val)) = $e.get($name) {
-            val
-        } else {
-            if let Some(ref def) = $opt {
-                def
-            } else {
-                return None;
+        match $e.get($name) {
+            Some(& $en::$t(ref val)) => val,
+            Some(_) => return Err(ErrorKind::Mistyped($name)),
+            None => {
+                if let Some(ref def) = $opt {
+                    def
+                } else {
+                    return Err(ErrorKind::Missing($name));
+                }
             }
         }
     )
 }
 
-pub fn parse_trace(s: &str) -> Vec<TraceStmt> {
-    let stmts = if let Json::Array(v) = Json::parse(s).expect("Wrong json format") {
-        v
-    } else {
-        panic!("Unsupported json, file should be wrapped in array!");
-    };
+/// How the malformed input is treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Any error stops the parsing
+    Strict,
+    /// Bad records are reported and skipped, truncated trace is accepted
+    Lenient,
+}
 
-    parse_stmts(stmts)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Reading from the input failed
+    Io(String),
+    /// Input is neither json array nor json lines
+    UnknownFormat,
+    /// Record is not a valid json
+    Syntax(String),
+    /// Record is not a json object
+    NotObject,
+    /// Required field is absent
+    Missing(&'static str),
+    /// Field has an unexpected type
+    Mistyped(&'static str),
+    /// Input ended in the middle of the trace
+    Truncated,
+    /// Unsupported version of the trace format
+    Version(usize),
+    /// Binary trace is damaged
+    Corrupted(String),
 }
 
-fn parse_addr(object: &HashMap<String, Json>, name: &str) -> Option<usize> {
-    match *parse!(object, name, None, Json::Number) {
-        Unsigned(v) => Some(v as usize),
-        _ => None,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Index of the record in the trace
+    pub index: usize,
+    /// Byte offset of the record in the input
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+impl ErrorKind {
+    /// Whether the parsing may proceed with the next record
+    fn recoverable(&self) -> bool {
+        matches!(
+            *self,
+            ErrorKind::Syntax(_) | ErrorKind::NotObject | ErrorKind::Missing(_) | ErrorKind::Mistyped(_)
+        )
     }
 }
 
-fn parse_stmts(stmts: Vec<Json>) -> Vec<TraceStmt> {
-    let mut parsed: Vec<TraceStmt> = Vec::new();
-    for stmt in stmts {
-        if let Json::Object(ref line) = stmt {
-            if let Some(s) = TraceStmt::new(line) {
-                parsed.push(s);
-            }
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Io(ref e) => write!(f, "read failed: {}", e),
+            ErrorKind::UnknownFormat => f.write_str("unknown trace format"),
+            ErrorKind::Syntax(ref e) => write!(f, "wrong json format: {}", e),
+            ErrorKind::NotObject => f.write_str("record is not an object"),
+            ErrorKind::Missing(name) => write!(f, "missing field `{}`", name),
+            ErrorKind::Mistyped(name) => write!(f, "field `{}` has wrong type", name),
+            ErrorKind::Truncated => f.write_str("trace is truncated"),
+            ErrorKind::Version(v) => write!(f, "unsupported trace version {}", v),
+            ErrorKind::Corrupted(ref e) => write!(f, "corrupted binary trace: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "record {} at offset {}: {}",
+            self.index,
+            self.offset,
+            self.kind
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+/// Input with the sniffed bytes put back in front
+pub type Sniffed<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Reader of a trace in any supported format
+pub enum Trace<R> {
+    Json(TraceReader<Sniffed<R>>),
+    Binary(binary::Reader<Sniffed<R>>),
+    Qemu(QemuReader<Sniffed<R>>),
+}
+
+impl<R: BufRead> Trace<R> {
+    /// Picks the reader by the first bytes of the input
+    pub fn new(mut input: R, mode: Mode) -> Trace<R> {
+        // A single fill_buf may return less than a prefix, e.g. on a pipe
+        let want = qemu::PREFIXES.iter().map(|x| x.len()).chain(Some(binary::MAGIC.len())).max().unwrap_or(0);
+        let mut head = Vec::with_capacity(want);
+        while head.len() < want {
+            let n = match input.fill_buf() {
+                Ok(buf) if !buf.is_empty() => {
+                    let n = buf.len().min(want - head.len());
+                    head.extend_from_slice(&buf[..n]);
+                    n
+                }
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Let the reader report it
+                Err(_) => break,
+            };
+            input.consume(n);
+        }
+        let binary = head.first() == binary::MAGIC.first();
+        let qemu = qemu::PREFIXES.iter().any(|x| head.starts_with(x.as_bytes()));
+        let input = io::Cursor::new(head).chain(input);
+        if binary {
+            Trace::Binary(binary::Reader::new(input, mode))
+        } else if qemu {
+            Trace::Qemu(QemuReader::new(input, mode))
         } else {
-            eprintln!("Err in parsing line: \'{}\'. Skipping...", stmt.to_string());
+            Trace::Json(TraceReader::new(input, mode))
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Trace<R> {
+    type Item = Result<TraceStmt, ParseError>;
+
+    fn next(&mut self) -> Option<Result<TraceStmt, ParseError>> {
+        match *self {
+            Trace::Json(ref mut r) => r.next(),
+            Trace::Binary(ref mut r) => r.next(),
+            Trace::Qemu(ref mut r) => r.next(),
+        }
+    }
+}
+
+/// Layout of the json trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Records are wrapped in a single array
+    Array,
+    /// One record per line
+    Lines,
+}
+
+/// Incremental reader of the json trace
+///
+/// Only a single record is kept in memory at a time, so the trace is never
+/// loaded as a whole. The format is picked by the first non-blank byte.
+pub struct TraceReader<R> {
+    input: R,
+    mode: Mode,
+    /// Format of the input, known after the first byte
+    format: Option<Format>,
+    /// Whether the closing bracket (or the end of input) is reached
+    done: bool,
+    /// Raw bytes of the current record
+    record: Vec<u8>,
+    /// Index of the current record
+    index: usize,
+    /// Number of consumed bytes
+    offset: usize,
+    /// Offset of the current record
+    start: usize,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    pub fn new(input: R, mode: Mode) -> TraceReader<R> {
+        TraceReader {
+            input,
+            mode,
+            format: None,
+            done: false,
+            record: Vec::new(),
+            index: 0,
+            offset: 0,
+            start: 0,
         }
     }
-    parsed
+
+    fn peek(&mut self) -> Result<Option<u8>, ErrorKind> {
+        match self.input.fill_buf() {
+            Ok(buf) => Ok(buf.first().cloned()),
+            Err(e) => Err(ErrorKind::Io(e.to_string())),
+        }
+    }
+
+    fn consume(&mut self) {
+        self.input.consume(1);
+        self.offset += 1;
+    }
+
+    /// Skips whitespaces and records separators
+    fn skip_blank(&mut self) -> Result<(), ErrorKind> {
+        while let Some(b) = self.peek()? {
+            let sep = b == b',' && self.format == Some(Format::Array);
+            if !(b.is_ascii_whitespace() || sep) {
+                break;
+            }
+            self.consume();
+        }
+        Ok(())
+    }
+
+    /// Reads the raw bytes of the next record into `self.record`
+    ///
+    /// Returns false if there are no more records.
+    fn read_record(&mut self) -> Result<bool, ErrorKind> {
+        self.start = self.offset;
+        if self.format.is_none() {
+            self.skip_blank()?;
+            self.format = match self.peek()? {
+                Some(b'[') => {
+                    self.consume();
+                    Some(Format::Array)
+                }
+                Some(b'{') => Some(Format::Lines),
+                Some(_) => return Err(ErrorKind::UnknownFormat),
+                None => return Ok(false),
+            };
+        }
+        match self.format {
+            Some(Format::Lines) => self.read_line(),
+            _ => self.read_element(),
+        }
+    }
+
+    /// Reads the next non-blank line
+    fn read_line(&mut self) -> Result<bool, ErrorKind> {
+        loop {
+            self.start = self.offset;
+            self.record.clear();
+            let n = self.input.read_until(b'\n', &mut self.record).map_err(
+                |e| ErrorKind::Io(e.to_string()),
+            )?;
+            self.offset += n;
+            if n == 0 {
+                return Ok(false);
+            }
+            if !self.record.iter().all(|b| b.is_ascii_whitespace()) {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Reads the next element of the array
+    fn read_element(&mut self) -> Result<bool, ErrorKind> {
+        self.skip_blank()?;
+        self.start = self.offset;
+        match self.peek()? {
+            Some(b']') => return Ok(false),
+            None => return Err(ErrorKind::Truncated),
+            _ => {}
+        }
+
+        self.record.clear();
+        let (mut depth, mut in_str, mut escaped) = (0usize, false, false);
+        loop {
+            let b = match self.peek()? {
+                Some(b) => b,
+                None if depth > 0 || in_str => return Err(ErrorKind::Truncated),
+                None => break,
+            };
+            if in_str {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_str = false;
+                }
+            } else {
+                match b {
+                    b'"' => in_str = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' if depth > 0 => depth -= 1,
+                    b',' | b']' if depth == 0 => break,
+                    _ => {}
+                }
+            }
+            self.record.push(b);
+            self.consume();
+            if depth == 0 && !in_str && (b == b'}' || b == b']') {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    fn parse_record(&self) -> Result<TraceStmt, ErrorKind> {
+        let s = str::from_utf8(&self.record).map_err(
+            |e| ErrorKind::Syntax(e.to_string()),
+        )?;
+        let stmt = Json::parse(s.trim()).map_err(
+            |e| ErrorKind::Syntax(format!("{:?}", e)),
+        )?;
+        parse_stmt(stmt)
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = Result<TraceStmt, ParseError>;
+
+    fn next(&mut self) -> Option<Result<TraceStmt, ParseError>> {
+        while !self.done {
+            let res = match self.read_record() {
+                Ok(true) => {
+                    let res = self.parse_record();
+                    // Unterminated last line is left by a killed tracer
+                    if self.format == Some(Format::Lines) && !self.record.ends_with(b"\n") {
+                        self.done = true;
+                        res.map_err(|_| ErrorKind::Truncated)
+                    } else {
+                        res
+                    }
+                }
+                Ok(false) => {
+                    self.done = true;
+                    break;
+                }
+                Err(e) => {
+                    self.done = true;
+                    Err(e)
+                }
+            };
+            let index = self.index;
+            self.index += 1;
+            let e = match res {
+                Ok(s) => return Some(Ok(s)),
+                Err(kind) => ParseError {
+                    index,
+                    offset: self.start,
+                    kind,
+                },
+            };
+            match self.mode {
+                Mode::Lenient if e.kind == ErrorKind::Truncated => {
+                    eprintln!("Warning: {}. Stopping...", e);
+                }
+                Mode::Lenient if e.kind.recoverable() => {
+                    eprintln!("Err in parsing {}. Skipping...", e);
+                }
+                _ => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_addr(object: &HashMap<String, Json>, name: &'static str) -> Result<usize, ErrorKind> {
+    match *parse!(object, name, None, Json::Number) {
+        Unsigned(v) => Ok(v as usize),
+        _ => Err(ErrorKind::Mistyped(name)),
+    }
+}
+
+fn parse_stmt(stmt: Json) -> Result<TraceStmt, ErrorKind> {
+    if let Json::Object(ref line) = stmt {
+        TraceStmt::new(line)
+    } else {
+        Err(ErrorKind::NotObject)
+    }
 }
 
 impl ForeignInfo {
-    fn new(object: &HashMap<String, Json>) -> Option<ForeignInfo> {
-        let _ = parse!(object, "isForeignBranch", None, Json::Boolean);
-        let addr = parse_addr(object, "foreignTargetAddress");
-        Some(ForeignInfo {
-            foreign_addr: get!(addr),
+    fn new(object: &HashMap<String, Json>) -> Result<Option<ForeignInfo>, ErrorKind> {
+        if !*parse!(object, "isForeignBranch", Some(false), Json::Boolean) {
+            return Ok(None);
+        }
+        Ok(Some(ForeignInfo {
+            foreign_addr: parse_addr(object, "foreignTargetAddress")?,
             foreign_name: String::from(
                 parse!(object, "foreignTargetName", None, Json::String).as_str(),
             ),
+            stub: false,
+        }))
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Writes the statements as a json array
+pub fn write_json<W: Write, I: Iterator<Item = TraceStmt>>(out: &mut W, stmts: I) -> io::Result<()> {
+    write!(out, "[")?;
+    for (i, stmt) in stmts.enumerate() {
+        let sep = if i == 0 { "" } else { ",\n " };
+        write!(out, "{}{}", sep, stmt.to_json())?;
+    }
+    writeln!(out, "]")
+}
+
+impl MemAccess {
+    fn new(access: &Json) -> Result<MemAccess, ErrorKind> {
+        let object = match *access {
+            Json::Object(ref x) => x,
+            _ => return Err(ErrorKind::Mistyped("memory")),
+        };
+        Ok(MemAccess {
+            addr: parse_addr(object, "address")?,
+            size: parse_addr(object, "size")?,
+            write: *parse!(object, "isWrite", Some(false), Json::Boolean),
         })
     }
 }
 
 impl TraceStmt {
-    fn new(object: &HashMap<String, Json>) -> Option<TraceStmt> {
-        let addr = parse_addr(object, "address");
-        Some(TraceStmt {
-            addr: get!(addr),
-            hex: String::from(parse!(object, "hexDump", None, Json::String).as_str()),
-            text: String::from(parse!(object, "text", None, Json::String).as_str()),
+    /// Serializes the statement as a json record
+    pub fn to_json(&self) -> String {
+        let mut s = format!(
+            "{{ \"address\": {}, \"hexDump\": {}, \"text\": {}",
+            self.addr,
+            escape(&self.hex),
+            escape(&self.text)
+        );
+        if self.isbr {
+            s.push_str(", \"isBranch\": true");
+        }
+        if let Some(ref f) = self.foreign {
+            s.push_str(&format!(
+                ", \"isForeignBranch\": true, \"foreignTargetAddress\": {}, \
+                 \"foreignTargetName\": {}",
+                f.foreign_addr,
+                escape(&f.foreign_name)
+            ));
+        }
+        if let Some(tid) = self.tid {
+            s.push_str(&format!(", \"tid\": {}", tid));
+        }
+        if !self.memory.is_empty() {
+            let memory: Vec<String> = self.memory
+                .iter()
+                .map(|m| {
+                    format!(
+                        "{{ \"address\": {}, \"size\": {}, \"isWrite\": {} }}",
+                        m.addr,
+                        m.size,
+                        m.write
+                    )
+                })
+                .collect();
+            s.push_str(&format!(", \"memory\": [{}]", memory.join(", ")));
+        }
+        s.push_str(" }");
+        s
+    }
+
+    fn new(object: &HashMap<String, Json>) -> Result<TraceStmt, ErrorKind> {
+        // Address-only record, the rest may be recovered from the binary
+        let bare = !object.contains_key("hexDump") && !object.contains_key("text");
+        let def = if bare { Some(String::new()) } else { None };
+        Ok(TraceStmt {
+            addr: parse_addr(object, "address")?,
+            hex: String::from(parse!(object, "hexDump", def, Json::String).as_str()),
+            text: String::from(parse!(object, "text", def, Json::String).as_str()),
             isbr: *parse!(object, "isBranch", Some(false), Json::Boolean),
-            foreign: ForeignInfo::new(object),
+            foreign: ForeignInfo::new(object)?,
+            tid: match object.get("tid") {
+                Some(&Json::Number(Unsigned(v))) => Some(v as usize),
+                Some(_) => return Err(ErrorKind::Mistyped("tid")),
+                None => None,
+            },
+            memory: match object.get("memory") {
+                Some(Json::Array(v)) => v.iter().map(MemAccess::new).collect::<Result<_, _>>()?,
+                Some(_) => return Err(ErrorKind::Mistyped("memory")),
+                None => Vec::new(),
+            },
+            decoded: Lazy::default(),
         })
     }
 }
 
 #[cfg(test)]
 pub mod test {
+    use itertools::Itertools;
     use parsing::*;
     use trace::TraceStmt;
     use std::cmp::Eq;
+    use std::io::BufReader;
     impl PartialEq for TraceStmt {
         fn eq(&self, other: &TraceStmt) -> bool {
             self.addr == other.addr && self.hex == other.hex && self.text == other.text &&
-                self.isbr == other.isbr && self.foreign == other.foreign
+                self.isbr == other.isbr && self.foreign == other.foreign && self.tid == other.tid &&
+                self.memory == other.memory
         }
     }
     impl Eq for TraceStmt {}
     impl PartialEq for ForeignInfo {
         fn eq(&self, other: &ForeignInfo) -> bool {
-            self.foreign_addr == other.foreign_addr && self.foreign_name == other.foreign_name
+            self.foreign_addr == other.foreign_addr && self.foreign_name == other.foreign_name &&
+                self.stub == other.stub
         }
     }
     impl Eq for ForeignInfo {}
@@ -119,6 +559,9 @@ pub mod test {
                 text: "xor ebp, ebp".to_string(),
                 isbr: false,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
             TraceStmt {
                 addr: 4195394,
@@ -126,6 +569,9 @@ pub mod test {
                 text: "mov r9, rdx".to_string(),
                 isbr: false,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
             TraceStmt {
                 addr: 4195397,
@@ -133,6 +579,9 @@ pub mod test {
                 text: "call 0x4195398".to_string(),
                 isbr: true,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
             TraceStmt {
                 addr: 4195398,
@@ -140,6 +589,9 @@ pub mod test {
                 text: "mov rdx, rsp".to_string(),
                 isbr: false,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
             TraceStmt {
                 addr: 4195401,
@@ -147,6 +599,9 @@ pub mod test {
                 text: "and rsp, 0xfffffffffffffff0".to_string(),
                 isbr: false,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
             TraceStmt {
                 addr: 4195405,
@@ -154,6 +609,9 @@ pub mod test {
                 text: "push rax".to_string(),
                 isbr: false,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
             TraceStmt {
                 addr: 4195406,
@@ -161,15 +619,186 @@ pub mod test {
                 text: "ret".to_string(),
                 isbr: true,
                 foreign: None,
+                tid: None,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
             },
         ]
     }
 
     #[test]
     fn trace_parsing() {
-        let trace = parse_trace(json_traces());
-        for (l, r) in trace.into_iter().zip(traces()) {
-            assert_eq!(l, r);
+        let trace = TraceReader::new(json_traces().as_bytes(), Mode::Strict);
+        for (l, r) in trace.zip(traces()) {
+            assert_eq!(l.unwrap(), r);
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        // Feed the reader byte by byte to cross every buffer boundary
+        let input = BufReader::with_capacity(1, json_traces().as_bytes());
+        let trace = TraceReader::new(input, Mode::Strict).collect::<Result<Vec<_>, _>>();
+        assert_eq!(trace.unwrap(), traces());
+    }
+
+    fn parse_with(s: &str, mode: Mode) -> Vec<Result<TraceStmt, ParseError>> {
+        TraceReader::new(s.as_bytes(), mode).collect()
+    }
+
+    #[test]
+    fn unknown_format() {
+        let res = parse_with(r#"  "address""#, Mode::Lenient);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].clone().unwrap_err().kind, ErrorKind::UnknownFormat);
+    }
+
+    #[test]
+    fn missing_field() {
+        let s = r#"[{ "address": 1, "hexDump": "90", "text": "nop" },
+                    { "address": 2, "text": "nop" },
+                    { "address": "3", "hexDump": "90", "text": "nop" },
+                    { "address": 4, "hexDump": "90", "text": "nop" }]"#;
+        let res = parse_with(s, Mode::Strict);
+        assert_eq!(res.len(), 2);
+        let e = res[1].clone().unwrap_err();
+        assert_eq!(e.index, 1);
+        assert_eq!(&s[e.offset..e.offset + 1], "{");
+        assert_eq!(e.kind, ErrorKind::Missing("hexDump"));
+
+        let res = parse_with(s, Mode::Lenient);
+        let addrs: Vec<usize> = res.into_iter().map(|x| x.unwrap().addr).collect();
+        assert_eq!(addrs, vec![1, 4]);
+    }
+
+    #[test]
+    fn address_only() {
+        let s = r#"[{ "address": 1 }, { "address": 2, "isBranch": true }]"#;
+        let res = parse_with(s, Mode::Strict);
+        let s = res[1].clone().unwrap();
+        assert_eq!((s.addr, s.hex.as_str(), s.text.as_str(), s.isbr), (2, "", "", true));
+    }
+
+    #[test]
+    fn mistyped_field() {
+        let s = r#"[{ "address": 1, "hexDump": "90", "text": false }]"#;
+        let e = parse_with(s, Mode::Strict)[0].clone().unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Mistyped("text"));
+        let s = r#"[{ "address": 1, "hexDump": "90", "text": "nop", "memory": [1] }]"#;
+        let e = parse_with(s, Mode::Strict)[0].clone().unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Mistyped("memory"));
+    }
+
+    #[test]
+    fn truncated() {
+        let full = json_traces();
+        // Cut in the middle of the 4th record
+        let cut = full.find("4889E2").unwrap();
+        let s = &full[..cut];
+
+        let res = parse_with(s, Mode::Strict);
+        assert_eq!(res.len(), 4);
+        let e = res[3].clone().unwrap_err();
+        assert_eq!(e.index, 3);
+        assert_eq!(e.kind, ErrorKind::Truncated);
+
+        let res = parse_with(s, Mode::Lenient);
+        assert_eq!(res.len(), 3);
+        assert!(res.into_iter().all(|x| x.is_ok()));
+
+        // Missing closing bracket only
+        let s = &full[..full.len() - 1];
+        let res = parse_with(s, Mode::Lenient);
+        assert_eq!(res.len(), 7);
+        let res = parse_with(s, Mode::Strict);
+        assert_eq!(res[7].clone().unwrap_err().kind, ErrorKind::Truncated);
+    }
+
+    #[test]
+    fn json_lines() {
+        let lines = json_traces()
+            .trim_matches(|c| c == '[' || c == ']')
+            .split(",\n")
+            .map(|x| x.trim())
+            .join("\n");
+        let res = parse_with(&lines, Mode::Strict);
+        assert_eq!(res.into_iter().map(|x| x.unwrap()).collect::<Vec<_>>(), traces());
+
+        // Killed in the middle of the last line
+        let cut = &lines[..lines.len() - 5];
+        let res = parse_with(cut, Mode::Strict);
+        assert_eq!(res.len(), 7);
+        let e = res[6].clone().unwrap_err();
+        assert_eq!((e.index, e.kind), (6, ErrorKind::Truncated));
+        assert_eq!(parse_with(cut, Mode::Lenient).len(), 6);
+
+        // Blank lines are skipped, bad lines are skipped in lenient mode only
+        let s = "\n{ \"address\": 1, \"hexDump\": \"90\", \"text\": \"nop\" }\n\n{ oops\n";
+        let res = parse_with(s, Mode::Lenient);
+        assert_eq!(res.len(), 1);
+        let res = parse_with(s, Mode::Strict);
+        let e = res[1].clone().unwrap_err();
+        assert_eq!(e.index, 1);
+        assert_eq!(&s[e.offset..e.offset + 6], "{ oops");
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let mut v = traces();
+        v[1].text = "\"quoted\" \\ \t".to_string();
+        v[2].foreign = Some(ForeignInfo {
+            foreign_addr: 4195398,
+            foreign_name: "puts".to_string(),
+            stub: false,
+        });
+        v[3].tid = Some(42);
+        v[5].memory = vec![
+            MemAccess {
+                addr: 0x7ffc0000,
+                size: 8,
+                write: false,
+            },
+            MemAccess {
+                addr: 0x601040,
+                size: 4,
+                write: true,
+            },
+        ];
+        let s = v.iter().map(|x| x.to_json()).join("\n");
+        let res = parse_with(&s, Mode::Strict);
+        assert_eq!(res.into_iter().map(|x| x.unwrap()).collect::<Vec<_>>(), v);
+    }
+
+    #[test]
+    fn json_array() {
+        for v in [Vec::new(), traces()] {
+            let mut out = Vec::new();
+            write_json(&mut out, v.clone().into_iter()).unwrap();
+            let s = String::from_utf8(out).unwrap();
+            let res = parse_with(&s, Mode::Strict);
+            assert_eq!(res.into_iter().map(|x| x.unwrap()).collect::<Vec<_>>(), v);
+        }
+    }
+
+    #[test]
+    fn sniffing() {
+        for cap in [1, 64] {
+            // Small capacity splits the prefix over several reads
+            let sniff = |s: &'static [u8]| match Trace::new(BufReader::with_capacity(cap, s), Mode::Strict) {
+                Trace::Json(_) => "json",
+                Trace::Binary(_) => "binary",
+                Trace::Qemu(_) => "qemu",
+            };
+            assert_eq!(sniff(b"[]"), "json");
+            assert_eq!(sniff(b"{}\n"), "json");
+            assert_eq!(sniff(binary::MAGIC), "binary");
+            assert_eq!(sniff(b"----------------\nIN: main\n"), "qemu");
+            assert_eq!(sniff(b"PROLOGUE:\n"), "qemu");
+
+            let mut out = Vec::new();
+            write_json(&mut out, traces().into_iter()).unwrap();
+            let res = Trace::new(BufReader::with_capacity(cap, &out[..]), Mode::Strict);
+            assert_eq!(res.map(|x| x.unwrap()).collect::<Vec<_>>(), traces());
         }
     }
 }
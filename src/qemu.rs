@@ -0,0 +1,326 @@
+//! Import of QEMU logs
+//!
+//! Translation blocks are taken from the `-d in_asm` disassembly and the
+//! execution order from `-d exec` (`Trace`/`Chained` lines) or, if there are
+//! none, from the `RIP=`/`EIP=` lines of `-d cpu` dumps. Use `-d nochain` so
+//! that every executed block is logged. The last instruction of each
+//! translation block is marked as a branch.
+
+use parsing::{ErrorKind, Mode, ParseError};
+use trace::TraceStmt;
+use base::Lazy;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::BufRead;
+
+/// Prefixes of the lines QEMU log may start with
+pub const PREFIXES: &[&str] = &[
+    "----------------",
+    "IN:",
+    "Trace ",
+    "Chained ",
+    "RAX=",
+    "EAX=",
+    "PROLOGUE:",
+];
+
+#[derive(Debug, Clone)]
+struct Instr {
+    addr: usize,
+    hex: String,
+    text: String,
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    let s = s.trim();
+    usize::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+/// Parses `0x0000000000401126:  48 89 e5  movq %rsp, %rbp`
+fn parse_instr(line: &str) -> Option<Instr> {
+    if !line.starts_with("0x") {
+        return None;
+    }
+    let colon = line.find(':')?;
+    let addr = parse_hex(&line[..colon])?;
+    let mut rest = line[colon + 1..].trim_start();
+    let mut hex = String::new();
+    // Bytes are printed by the newer versions only
+    loop {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let tok = &rest[..end];
+        if tok.len() != 2 || !tok.chars().all(|c| c.is_ascii_hexdigit()) {
+            break;
+        }
+        hex.push_str(&tok.to_uppercase());
+        rest = rest[end..].trim_start();
+    }
+    Some(Instr {
+        addr,
+        hex,
+        text: rest.split_whitespace().collect::<Vec<_>>().join(" "),
+    })
+}
+
+/// Parses the executed block of `Trace 0: 0x7f.. [00000000/0000000000401126/..] main`
+/// or `Trace 0x7f.. [0000000000401126] main`, returns the cpu and pc
+fn parse_exec(line: &str) -> Option<(Option<usize>, usize)> {
+    let rest = line.strip_prefix("Trace ").or_else(|| line.strip_prefix("Chained "))?;
+    let cpu = rest.find(':').and_then(|i| rest[..i].trim().parse().ok());
+    let open = rest.find('[')?;
+    let close = open + rest[open..].find(']')?;
+    let fields: Vec<&str> = rest[open + 1..close].split('/').collect();
+    let pc = if fields.len() > 1 { fields[1] } else { fields[0] };
+    Some((cpu, parse_hex(pc)?))
+}
+
+/// Parses the pc of `RAX=... ` dump line `RIP=000000000040112a RFL=...`
+fn parse_cpu(line: &str) -> Option<usize> {
+    let i = line.find("RIP=").or_else(|| line.find("EIP="))?;
+    let pc = line[i + 4..].split_whitespace().next()?;
+    parse_hex(pc)
+}
+
+fn syntax(msg: &str) -> ErrorKind {
+    ErrorKind::Syntax(msg.to_string())
+}
+
+/// Incremental reader of the QEMU log
+pub struct QemuReader<R> {
+    input: R,
+    mode: Mode,
+    done: bool,
+    line: String,
+    /// Latest translation of every block
+    blocks: HashMap<usize, Vec<Instr>>,
+    /// Block being disassembled
+    current: Option<Vec<Instr>>,
+    /// Whether the exec lines are present
+    exec: bool,
+    /// Statements of the executed block, not yet yielded
+    queue: VecDeque<TraceStmt>,
+    /// Number of yielded statements
+    index: usize,
+    /// Number of consumed bytes
+    offset: usize,
+}
+
+impl<R: BufRead> QemuReader<R> {
+    pub fn new(input: R, mode: Mode) -> QemuReader<R> {
+        QemuReader {
+            input,
+            mode,
+            done: false,
+            line: String::new(),
+            blocks: HashMap::new(),
+            current: None,
+            exec: false,
+            queue: VecDeque::new(),
+            index: 0,
+            offset: 0,
+        }
+    }
+
+    fn close_block(&mut self) {
+        if let Some(b) = self.current.take() {
+            if let Some(addr) = b.first().map(|x| x.addr) {
+                self.blocks.insert(addr, b);
+            }
+        }
+    }
+
+    fn execute(&mut self, tid: Option<usize>, pc: usize) {
+        let stmt = |x: &Instr, isbr| {
+            TraceStmt {
+                addr: x.addr,
+                hex: x.hex.clone(),
+                text: x.text.clone(),
+                isbr,
+                foreign: None,
+                tid,
+                memory: Vec::new(),
+                decoded: Lazy::default(),
+            }
+        };
+        match self.blocks.get(&pc) {
+            Some(b) => {
+                let last = b.len() - 1;
+                self.queue.extend(b.iter().enumerate().map(|(i, x)| stmt(x, i == last)));
+            }
+            // Translated before the log was started
+            None => {
+                let x = Instr {
+                    addr: pc,
+                    hex: String::new(),
+                    text: String::new(),
+                };
+                self.queue.push_back(stmt(&x, true));
+            }
+        }
+    }
+
+    /// Handles the line, the ones looking known but not parsed are errors
+    fn handle_line(&mut self) -> Result<(), ErrorKind> {
+        let line = self.line.trim_end().to_string();
+        if self.current.is_some() {
+            if line.starts_with("0x") {
+                let i = parse_instr(&line).ok_or_else(|| syntax("wrong instruction line"))?;
+                self.current.as_mut().unwrap().push(i);
+                return Ok(());
+            }
+            self.close_block();
+        }
+        if line.starts_with("IN:") {
+            self.current = Some(Vec::new());
+        } else if line.starts_with("Trace ") || line.starts_with("Chained ") {
+            let (cpu, pc) = parse_exec(&line).ok_or_else(|| syntax("wrong exec line"))?;
+            self.exec = true;
+            self.execute(cpu, pc);
+        } else if !self.exec && (line.starts_with("RIP=") || line.starts_with("EIP=")) {
+            let pc = parse_cpu(&line).ok_or_else(|| syntax("wrong cpu line"))?;
+            self.execute(None, pc);
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for QemuReader<R> {
+    type Item = Result<TraceStmt, ParseError>;
+
+    fn next(&mut self) -> Option<Result<TraceStmt, ParseError>> {
+        while self.queue.is_empty() && !self.done {
+            self.line.clear();
+            match self.input.read_line(&mut self.line) {
+                Ok(0) => self.done = true,
+                Ok(n) => {
+                    let start = self.offset;
+                    self.offset += n;
+                    if let Err(kind) = self.handle_line() {
+                        let e = ParseError {
+                            index: self.index,
+                            offset: start,
+                            kind,
+                        };
+                        if self.mode == Mode::Lenient {
+                            eprintln!("Err in parsing {}. Skipping...", e);
+                        } else {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(ParseError {
+                        index: self.index,
+                        offset: self.offset,
+                        kind: ErrorKind::Io(e.to_string()),
+                    }));
+                }
+            }
+        }
+        let s = self.queue.pop_front()?;
+        self.index += 1;
+        Some(Ok(s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use qemu::*;
+    use trace::Bb;
+
+    fn read(log: &str) -> Vec<TraceStmt> {
+        QemuReader::new(log.as_bytes(), Mode::Strict).map(|x| x.unwrap()).collect()
+    }
+
+    const BROKEN: &str = "IN: main
+0x0000000000401126:  90                       nop
+0x00000000004011zz:  c3                       retq
+
+Trace 0: 0x7f4c84000100 [00000000/0000000000401126/00000000/ff200000] main
+Trace 0: 0x7f4c84000100 [00000000/00000000004011zz
+Trace 0: 0x7f4c84000100 [00000000/0000000000401126/00000000/ff200000] main
+";
+
+    #[test]
+    fn exec_in_asm() {
+        let log = "----------------
+IN: main
+0x0000000000401126:  55                       pushq    %rbp
+0x0000000000401127:  48 89 e5                 movq     %rsp, %rbp
+0x000000000040112a:  e8 f1 ff ff ff           callq    0x401120
+
+Trace 0: 0x7f4c84000100 [00000000/0000000000401126/00000000/ff200000] main
+----------------
+IN: foo
+0x0000000000401120:  c3                       retq
+
+Trace 0: 0x7f4c84000200 [00000000/0000000000401120/00000000/ff200000] foo
+Trace 1: 0x7f4c84000100 [00000000/0000000000401126/00000000/ff200000] main
+";
+        let trace = read(log);
+        assert_eq!(trace.len(), 7);
+        assert_eq!(trace[1].addr, 0x401127);
+        assert_eq!(trace[1].hex, "4889E5");
+        assert_eq!(trace[1].text, "movq %rsp, %rbp");
+        assert_eq!(
+            trace.iter().map(|x| x.isbr).collect::<Vec<_>>(),
+            vec![false, false, true, true, false, false, true]
+        );
+        assert_eq!(trace[6].tid, Some(1));
+
+        let bbs: Vec<Bb> = Bb::new(trace).collect();
+        assert_eq!(bbs.len(), 3);
+    }
+
+    #[test]
+    fn old_format() {
+        let log = "IN:
+0x00000000004000b0:  xor    %ebp,%ebp
+0x00000000004000b2:  jmp    0x4000b0
+
+Trace 0x7f1a2c000010 [00000000004000b0]
+Trace 0x7f1a2c000010 [00000000004000b0]
+";
+        let trace = read(log);
+        assert_eq!(trace.len(), 4);
+        assert_eq!(trace[0].text, "xor %ebp,%ebp");
+        assert_eq!(trace[0].hex, "");
+        assert_eq!(trace[0].tid, None);
+        assert!(trace[3].isbr);
+    }
+
+    #[test]
+    fn cpu_dumps() {
+        let log = "IN:
+0x00000000004000b0:  90                       nop
+0x00000000004000b1:  c3                       retq
+
+RAX=0000000000000000 RBX=0000000000000000 RCX=0000000000000000 RDX=0000000000000000
+RIP=00000000004000b0 RFL=00000202 [-------] CPL=3 II=0 A20=1 SMM=0 HLT=0
+RIP=0000000000400100 RFL=00000202 [-------] CPL=3 II=0 A20=1 SMM=0 HLT=0
+";
+        let trace = read(log);
+        assert_eq!(trace.len(), 3);
+        // Unknown block is kept as a single branch
+        assert_eq!(trace[2].addr, 0x400100);
+        assert!(trace[2].isbr);
+    }
+
+    #[test]
+    fn strict() {
+        let res: Vec<_> = QemuReader::new(BROKEN.as_bytes(), Mode::Strict).collect();
+        assert_eq!(res.len(), 1);
+        let e = res[0].clone().unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Syntax("wrong instruction line".to_string()));
+        assert_eq!(&BROKEN[e.offset..e.offset + 4], "0x00");
+    }
+
+    #[test]
+    fn lenient() {
+        let res: Vec<_> = QemuReader::new(BROKEN.as_bytes(), Mode::Lenient).collect();
+        let addrs: Vec<usize> = res.into_iter().map(|x| x.unwrap().addr).collect();
+        assert_eq!(addrs, vec![0x401126, 0x401126]);
+    }
+}
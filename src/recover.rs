@@ -0,0 +1,148 @@
+//! Recovery of the instructions for address-only traces
+//!
+//! The traced binary and its shared objects are read from disk, the bytes
+//! at every traced address are disassembled to fill the hexdump and the text.
+//! Branch flag is taken from the decoded instruction as well.
+
+use base::{Instr, Lazy};
+use disasm;
+use image::Image;
+use trace::TraceStmt;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+/// Longest x86 instruction
+const MAX_LEN: usize = 15;
+
+struct Mapped {
+    image: Image,
+    /// Difference between the runtime and the link-time addresses
+    bias: usize,
+}
+
+pub struct Recover {
+    images: Vec<Mapped>,
+    cache: HashMap<usize, Option<Instr>>,
+}
+
+impl Recover {
+    pub fn new() -> Recover {
+        Recover {
+            images: Vec::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Adds the image loaded at runtime `base`, link-time base if `None`
+    pub fn add(&mut self, image: Image, base: Option<usize>) {
+        let bias = base.map_or(0, |x| x.wrapping_sub(image.base));
+        self.images.push(Mapped {
+            image,
+            bias,
+        });
+    }
+
+    /// Adds the executable images of `/proc/<pid>/maps` listing
+    pub fn add_maps<R: BufRead>(&mut self, maps: R) -> io::Result<()> {
+        for line in maps.lines() {
+            let line = line?;
+            // 555555554000-555555556000 r-xp 00000000 08:01 1234  /bin/true
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || !fields[5].starts_with('/') {
+                continue;
+            }
+            let start = fields[0].split('-').next().and_then(|x| {
+                usize::from_str_radix(x, 16).ok()
+            });
+            let offset = usize::from_str_radix(fields[2], 16).ok();
+            // The first mapping of the file is the image base
+            if let (Some(start), Some(0)) = (start, offset) {
+                match Image::load(fields[5]) {
+                    Ok(img) => self.add(img, Some(start)),
+                    Err(e) => eprintln!("Can't load {}: {}", fields[5], e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&self, addr: usize) -> Option<Instr> {
+        self.images.iter().filter_map(|m| {
+            let code = m.image.bytes(addr.wrapping_sub(m.bias))?;
+            let code = &code[..MAX_LEN.min(code.len())];
+            let mut instr = disasm::decode(code, addr, m.image.bitness).into_iter().next()?;
+            instr.addr = addr;
+            Some(instr)
+        }).next()
+    }
+
+    /// Fills the statement without hexdump from the images
+    ///
+    /// Returns false if the address is not mapped by any image.
+    pub fn fill(&mut self, stmt: &mut TraceStmt) -> bool {
+        if !stmt.hex.is_empty() {
+            return true;
+        }
+        if !self.cache.contains_key(&stmt.addr) {
+            let instr = self.decode(stmt.addr);
+            if instr.is_none() {
+                eprintln!("Can't recover instr at {:x}", stmt.addr);
+            }
+            self.cache.insert(stmt.addr, instr);
+        }
+        match self.cache[&stmt.addr] {
+            Some(ref i) => {
+                stmt.hex = i.hex.clone();
+                stmt.text = i.text.clone();
+                stmt.isbr = i.isbr;
+                stmt.decoded = Lazy::default();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use recover::*;
+    use image::test::{temp_elf, tiny_elf};
+    use trace::test::at;
+
+    // xor ebp, ebp; call $+5; ret
+    const CODE: &[u8] = &[0x31, 0xed, 0xe8, 0x00, 0x00, 0x00, 0x00, 0xc3];
+
+    #[test]
+    fn link_address() {
+        let mut rec = Recover::new();
+        rec.add(Image::parse("tiny", &tiny_elf(0x400000, CODE)).unwrap(), None);
+        let mut s = at(0x400002);
+        assert!(rec.fill(&mut s));
+        assert_eq!((s.hex.as_str(), s.text.as_str(), s.isbr), ("E800000000", "call 0x400007", true));
+        let mut s = at(0x400000);
+        assert!(rec.fill(&mut s));
+        assert_eq!((s.text.as_str(), s.isbr), ("xor ebp, ebp", false));
+        assert!(!rec.fill(&mut at(0x500000)));
+    }
+
+    #[test]
+    fn maps() {
+        let path = temp_elf("recover", 0x400000, CODE);
+        let maps = format!(
+            "555555554000-555555555000 r-xp 00000000 08:01 1234    {}\n\
+             7ffffffde000-7ffffffff000 rw-p 00000000 00:00 0       [stack]\n",
+            path.to_str().unwrap()
+        );
+        let mut rec = Recover::new();
+        rec.add_maps(maps.as_bytes()).unwrap();
+        let mut s = at(0x555555554007);
+        assert!(rec.fill(&mut s));
+        assert_eq!(s.text, "ret");
+        assert!(s.isbr);
+    }
+}
@@ -0,0 +1,450 @@
+//! Structural analysis of the traced graph
+//!
+//! The graph is reduced by collapsing the single-entry single-exit patterns
+//! into regions until nothing matches. The nodes left are wrapped into an
+//! unstructured region, so the result is always a single tree.
+
+use cfg::{Cfg, EdgeKind, Key, NodeBase};
+use disasm::Flow;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Block,
+    Sequence,
+    IfThen,
+    IfThenElse,
+    /// Block jumping to itself
+    SelfLoop,
+    /// Condition followed by the body jumping back to it
+    While,
+    /// Body followed by the condition jumping back to it
+    DoWhile,
+    /// Indirect jump to several cases joining at the same node
+    Switch,
+    Unstructured,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Kind::Block => "block",
+            Kind::Sequence => "sequence",
+            Kind::IfThen => "if-then",
+            Kind::IfThenElse => "if-then-else",
+            Kind::SelfLoop => "self-loop",
+            Kind::While => "while",
+            Kind::DoWhile => "do-while",
+            Kind::Switch => "switch",
+            Kind::Unstructured => "unstructured",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Region {
+    pub kind: Kind,
+    /// Vertex the region is entered at
+    pub entry: Key,
+    /// Nested regions, the condition is the first one of a conditional or
+    /// a while, the last one of a do-while
+    pub children: Vec<Region>,
+}
+
+/// Graph of the regions being reduced
+struct Reduction<'a> {
+    cfg: &'a Cfg,
+    regions: BTreeMap<usize, Region>,
+    succs: BTreeMap<usize, BTreeSet<usize>>,
+    preds: BTreeMap<usize, BTreeSet<usize>>,
+    entry: usize,
+}
+
+impl<'a> Reduction<'a> {
+    fn new(cfg: &'a Cfg, entry: Key) -> Reduction<'a> {
+        let ids: BTreeMap<Key, usize> = cfg.verts.keys().enumerate().map(|(i, &k)| (k, i)).collect();
+        let mut r = Reduction {
+            cfg,
+            regions: BTreeMap::new(),
+            succs: BTreeMap::new(),
+            preds: BTreeMap::new(),
+            entry: ids[&entry],
+        };
+        for (&k, &i) in ids.iter() {
+            r.regions.insert(i, Region::block(k));
+            r.succs.insert(i, cfg.succs(k).iter().map(|x| ids[x]).collect());
+            r.preds.insert(i, BTreeSet::new());
+        }
+        for (&k, &i) in ids.iter() {
+            for x in cfg.succs(k) {
+                r.preds.get_mut(&ids[&x]).unwrap().insert(i);
+            }
+        }
+        r
+    }
+
+    /// Nodes in postorder from the entry, the unreachable ones come last
+    fn postorder(&self) -> Vec<usize> {
+        let mut res = Vec::new();
+        let mut seen = BTreeSet::new();
+        let roots = Some(self.entry).into_iter().chain(self.regions.keys().cloned());
+        for root in roots {
+            if !seen.insert(root) {
+                continue;
+            }
+            let mut stack = vec![(root, self.succs[&root].iter().rev().cloned().collect::<Vec<_>>())];
+            while let Some(&mut (x, ref mut next)) = stack.last_mut() {
+                match next.pop() {
+                    Some(y) => {
+                        if seen.insert(y) {
+                            let next = self.succs[&y].iter().rev().cloned().collect();
+                            stack.push((y, next));
+                        }
+                    }
+                    None => {
+                        res.push(x);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Whether `m` is entered from `n` only, the entry is entered from outside
+    fn only_pred(&self, m: usize, n: usize) -> bool {
+        m != self.entry && self.preds[&m].len() == 1 && self.preds[&m].contains(&n)
+    }
+
+    /// Whether `n` dispatches through an indirect jump
+    fn indirect(&self, n: usize) -> bool {
+        self.regions[&n].blocks().into_iter().any(|v| match self.cfg.verts[&v].node {
+            NodeBase::Block(ref b) => {
+                self.cfg.succs(v).len() > 1 &&
+                    b.instrs.last().is_some_and(|x| x.flow().0 == Flow::IndirectJump)
+            }
+            _ => false,
+        })
+    }
+
+    /// Replaces the `nodes` with the region of them at the first one
+    fn collapse(&mut self, kind: Kind, nodes: Vec<usize>, succs: BTreeSet<usize>) {
+        let n = nodes[0];
+        let outer: BTreeSet<usize> = self.preds[&n].iter().filter(|x| !nodes.contains(x)).cloned().collect();
+        let mut children = Vec::new();
+        for x in nodes.iter() {
+            let r = self.regions.remove(x).unwrap();
+            if kind == Kind::Sequence && r.kind == Kind::Sequence {
+                children.extend(r.children);
+            } else {
+                children.push(r);
+            }
+            for s in self.succs.remove(x).unwrap() {
+                if let Some(p) = self.preds.get_mut(&s) {
+                    p.remove(x);
+                }
+            }
+            for p in self.preds.remove(x).unwrap() {
+                if let Some(s) = self.succs.get_mut(&p) {
+                    s.remove(x);
+                }
+            }
+        }
+        for &s in succs.iter() {
+            self.preds.get_mut(&s).unwrap().insert(n);
+        }
+        for &p in outer.iter() {
+            self.succs.get_mut(&p).unwrap().insert(n);
+        }
+        self.succs.insert(n, succs);
+        self.preds.insert(n, outer);
+        let entry = children[0].entry;
+        self.regions.insert(
+            n,
+            Region {
+                kind,
+                entry,
+                children,
+            },
+        );
+    }
+
+    /// Collapses the region headed by `n` if there is one
+    fn reduce(&mut self, n: usize) -> bool {
+        let succs: Vec<usize> = self.succs[&n].iter().cloned().collect();
+        let without = |x: &BTreeSet<usize>, y| x.iter().filter(|&&z| z != y).cloned().collect::<BTreeSet<_>>();
+        if succs.contains(&n) {
+            let rest = without(&self.succs[&n], n);
+            self.collapse(Kind::SelfLoop, vec![n], rest);
+            return true;
+        }
+        if succs.len() > 1 && self.indirect(n) {
+            return self.switch(n, succs);
+        }
+        match succs.len() {
+            1 => {
+                let m = succs[0];
+                if !self.only_pred(m, n) {
+                    return false;
+                }
+                let kind = if self.succs[&m].contains(&n) {
+                    Kind::DoWhile
+                } else {
+                    Kind::Sequence
+                };
+                let rest = without(&self.succs[&m], n);
+                self.collapse(kind, vec![n, m], rest);
+                true
+            }
+            2 => {
+                for &(a, b) in [(succs[0], succs[1]), (succs[1], succs[0])].iter() {
+                    if !self.only_pred(a, n) {
+                        continue;
+                    }
+                    let (sa, sb) = (self.succs[&a].clone(), self.succs[&b].clone());
+                    if sa.len() == 1 && sa.contains(&n) {
+                        self.collapse(Kind::While, vec![n, a], Some(b).into_iter().collect());
+                        return true;
+                    }
+                    if sa.len() == 1 && sa.contains(&b) && b != n {
+                        self.collapse(Kind::IfThen, vec![n, a], sa);
+                        return true;
+                    }
+                    if self.only_pred(b, n) && sa == sb && sa.len() <= 1 && !sa.contains(&n) {
+                        let (then, other) = if taken(self.cfg, &self.regions[&n], &self.regions[&a]) {
+                            (b, a)
+                        } else {
+                            (a, b)
+                        };
+                        self.collapse(Kind::IfThenElse, vec![n, then, other], sa);
+                        return true;
+                    }
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Collapses the cases of the indirect jump if they join at one node
+    fn switch(&mut self, n: usize, succs: Vec<usize>) -> bool {
+        let cases: Vec<usize> = succs.iter().cloned().filter(|&x| self.only_pred(x, n)).collect();
+        // Join of the cases along with the targets entered from elsewhere too
+        let exits: BTreeSet<usize> = cases
+            .iter()
+            .flat_map(|x| self.succs[x].iter().cloned())
+            .chain(succs.iter().cloned().filter(|x| !cases.contains(x)))
+            .collect();
+        if cases.is_empty() || exits.len() > 1 || exits.contains(&n) {
+            return false;
+        }
+        let mut nodes = vec![n];
+        nodes.extend(cases);
+        self.collapse(Kind::Switch, nodes, exits);
+        true
+    }
+}
+
+impl Region {
+    fn block(k: Key) -> Region {
+        Region {
+            kind: Kind::Block,
+            entry: k,
+            children: Vec::new(),
+        }
+    }
+
+    /// Recovers the regions of the graph entered at `entry`
+    pub fn new(cfg: &Cfg, entry: Key) -> Region {
+        let mut r = Reduction::new(cfg, entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for n in r.postorder() {
+                if r.regions.contains_key(&n) && r.reduce(n) {
+                    changed = true;
+                }
+            }
+        }
+        if r.regions.len() == 1 {
+            return r.regions.into_iter().next().unwrap().1;
+        }
+        let mut order = r.postorder();
+        order.reverse();
+        let children: Vec<Region> = order.into_iter().map(|x| r.regions.remove(&x).unwrap()).collect();
+        Region {
+            kind: Kind::Unstructured,
+            entry,
+            children,
+        }
+    }
+
+    /// Vertices of the region
+    pub fn blocks(&self) -> Vec<Key> {
+        match self.kind {
+            Kind::Block => vec![self.entry],
+            _ => self.children.iter().flat_map(|x| x.blocks()).collect(),
+        }
+    }
+
+    /// Writes the region as indented pseudo-structured code
+    pub fn write_code<W: Write>(&self, cfg: &Cfg, out: &mut W, depth: usize) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+        let body = |out: &mut W, x: &Region| x.write_code(cfg, out, depth + 1);
+        let ch = &self.children;
+        match self.kind {
+            Kind::Block => {
+                match cfg.verts[&self.entry].node {
+                    NodeBase::Block(ref b) => {
+                        for i in b.instrs.iter() {
+                            writeln!(out, "{}{:x}  {}", indent, i.addr, i.text)?;
+                        }
+                    }
+                    NodeBase::Foreign(ref f) => writeln!(out, "{}[{}]", indent, f.foreign_name)?,
+                }
+                return Ok(());
+            }
+            Kind::Sequence => {
+                for x in ch.iter() {
+                    x.write_code(cfg, out, depth)?;
+                }
+                return Ok(());
+            }
+            Kind::IfThen | Kind::IfThenElse | Kind::Switch => {
+                ch[0].write_code(cfg, out, depth)?;
+                let taken = taken(cfg, &ch[0], &ch[1]);
+                match self.kind {
+                    Kind::IfThen => writeln!(out, "{}if {} {{", indent, if taken { "taken" } else { "not taken" })?,
+                    Kind::IfThenElse => writeln!(out, "{}if not taken {{", indent)?,
+                    _ => writeln!(out, "{}switch {{", indent)?,
+                }
+                for (i, x) in ch[1..].iter().enumerate() {
+                    match self.kind {
+                        Kind::Switch => writeln!(out, "{}case {}:", indent, x.entry)?,
+                        Kind::IfThenElse if i == 1 => writeln!(out, "{}}} else {{", indent)?,
+                        _ => (),
+                    }
+                    body(out, x)?;
+                }
+            }
+            Kind::SelfLoop | Kind::Unstructured => {
+                writeln!(out, "{}{} {{", indent, if self.kind == Kind::SelfLoop { "loop" } else { "unstructured" })?;
+                for x in ch.iter() {
+                    body(out, x)?;
+                }
+            }
+            Kind::While => {
+                writeln!(out, "{}while {{", indent)?;
+                body(out, &ch[0])?;
+                writeln!(out, "{}}} do {{", indent)?;
+                body(out, &ch[1])?;
+            }
+            Kind::DoWhile => {
+                writeln!(out, "{}do {{", indent)?;
+                body(out, &ch[0])?;
+                writeln!(out, "{}}} while {{", indent)?;
+                body(out, &ch[1])?;
+            }
+        }
+        writeln!(out, "{}}}", indent)
+    }
+}
+
+/// Whether the region `b` is entered from `a` by a taken conditional branch
+fn taken(cfg: &Cfg, a: &Region, b: &Region) -> bool {
+    a.blocks().into_iter().any(|v| {
+        cfg.edges.get(&v).and_then(|x| x.get(&b.entry)).is_some_and(|e| {
+            e.kind == EdgeKind::Taken
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use regions::*;
+    use trace::{Bb, TraceStmt};
+    use trace::test::stmt;
+
+    fn kinds(r: &Region) -> String {
+        match r.kind {
+            Kind::Block => format!("{:x}", r.entry.addr),
+            k => format!("{}({})", k, r.children.iter().map(kinds).collect::<Vec<_>>().join(" ")),
+        }
+    }
+
+    fn structure(trace: Vec<TraceStmt>) -> (Cfg, Region) {
+        let cfg = Cfg::from_blocks(Bb::new(trace));
+        let r = Region::new(&cfg, cfg.entry().unwrap());
+        (cfg, r)
+    }
+
+    #[test]
+    fn conditionals() {
+        // 0: jz 4; 2: jmp 5; 4: nop; 5: jz 8; 7: nop; 8: ret
+        let jz0 = stmt(0x0, "7402", "jz 0x4");
+        let jmp = stmt(0x2, "EB01", "jmp 0x5");
+        let nop4 = stmt(0x4, "90", "nop");
+        let jz5 = stmt(0x5, "7401", "jz 0x8");
+        let nop7 = stmt(0x7, "90", "nop");
+        let ret = stmt(0x8, "C3", "ret");
+        let mut trace = vec![jz0.clone(), jmp, jz5.clone(), nop7, ret.clone()];
+        // The other path in another thread, so that it is not a loop
+        for mut s in [jz0, nop4, jz5, ret] {
+            s.tid = Some(1);
+            trace.push(s);
+        }
+        let (cfg, r) = structure(trace);
+        assert_eq!(kinds(&r), "sequence(if-then-else(0 2 4) if-then(5 7) 8)");
+        let mut code = Vec::new();
+        r.write_code(&cfg, &mut code, 0).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        assert!(code.starts_with("0  jz 0x4\nif not taken {\n    2  jmp 0x5\n} else {\n    4  nop\n}\n"));
+        assert!(code.contains("5  jz 0x8\nif not taken {\n    7  nop\n}\n8  ret\n"));
+    }
+
+    #[test]
+    fn loops() {
+        // 0: jz 4; 2: jmp 0 (while); 4: jnz 4 (self-loop);
+        // 6: jmp 9; 9: jnz 6 (do-while); b: ret
+        let jz = stmt(0x0, "7402", "jz 0x4");
+        let jmp0 = stmt(0x2, "EBFC", "jmp 0x0");
+        let jnz4 = stmt(0x4, "75FE", "jnz 0x4");
+        let jmp9 = stmt(0x6, "EB01", "jmp 0x9");
+        let jnz6 = stmt(0x9, "75FB", "jnz 0x6");
+        let ret = stmt(0xb, "C3", "ret");
+        let mut trace = vec![jz.clone(), jmp0, jz, jnz4.clone(), jnz4];
+        for _ in 0..2 {
+            trace.push(jmp9.clone());
+            trace.push(jnz6.clone());
+        }
+        trace.push(ret);
+        let (_, r) = structure(trace);
+        assert_eq!(kinds(&r), "sequence(while(0 2) self-loop(4) do-while(6 9) b)");
+    }
+
+    #[test]
+    fn switch() {
+        // 0: jmp rax to 2, 4 or 6, all of them jump to 8, in a thread each
+        let mut trace = Vec::new();
+        for &case in [2, 4, 6].iter() {
+            for mut s in [stmt(0x0, "FFE0", "jmp rax"), stmt(case, "EB00", "jmp 0x8"), stmt(0x8, "C3", "ret")] {
+                s.tid = Some(case);
+                trace.push(s);
+            }
+        }
+        let (_, r) = structure(trace);
+        assert_eq!(kinds(&r), "sequence(switch(0 2 4 6) 8)");
+        // The same in a loop, 8 jumps back to 0
+        let mut trace = Vec::new();
+        for &case in [2, 4, 6].iter() {
+            trace.push(stmt(0x0, "FFE0", "jmp rax"));
+            trace.push(stmt(case, "EB00", "jmp 0x8"));
+            trace.push(stmt(0x8, "EBF6", "jmp 0x0"));
+        }
+        let (_, r) = structure(trace);
+        assert_eq!(kinds(&r), "do-while(switch(0 2 4 6) 8)");
+    }
+}
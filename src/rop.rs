@@ -0,0 +1,200 @@
+//! Detection of the return and jump oriented programming
+//!
+//! Suspicious are the returns not to the instr after a pending call of the
+//! thread and the indirect jumps into the middle of an already executed
+//! block. Returns of a thread with no pending call are not reported, their
+//! callers are not traced.
+
+use base::Addressable;
+use disasm::Flow;
+use trace::Bb;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+/// Number of blocks reported before and after the suspicious transition
+pub const CONTEXT: usize = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// Return to an address no pending call of the thread returns to
+    Return,
+    /// Indirect jump into the block started at the address
+    MidBlock(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Suspicious {
+    pub reason: Reason,
+    /// Address of the branch
+    pub site: usize,
+    pub target: usize,
+    /// Trace index of the target block
+    pub index: usize,
+    /// Position of the target block in the blocks
+    pub block: usize,
+    /// Surrounding blocks, the target one included
+    pub context: Vec<Bb>,
+}
+
+impl fmt::Display for Suspicious {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            Reason::Return => write!(f, "return to no call site")?,
+            Reason::MidBlock(b) => write!(f, "jump into the middle of {:x}", b)?,
+        }
+        write!(f, " {:x} -> {:x} at {}", self.site, self.target, self.index)
+    }
+}
+
+/// Finds the suspicious transitions between the blocks of every thread
+///
+/// Only the last `context` blocks are kept, a transition is reported once
+/// the blocks after it are pushed.
+pub struct Detector {
+    context: usize,
+    /// Return sites of the pending calls of every thread, the last on top
+    calls: HashMap<Option<usize>, Vec<usize>>,
+    /// Executed blocks by the start, the last instr address as value
+    blocks: BTreeMap<usize, usize>,
+    /// Address and flow of the terminating instr of every thread
+    prev: HashMap<Option<usize>, (usize, Flow)>,
+    recent: VecDeque<Bb>,
+    /// Transitions waiting for the blocks after them
+    pending: Vec<Suspicious>,
+    /// Number of the blocks pushed
+    count: usize,
+}
+
+impl Detector {
+    pub fn new(context: usize) -> Detector {
+        Detector {
+            context,
+            calls: HashMap::new(),
+            blocks: BTreeMap::new(),
+            prev: HashMap::new(),
+            recent: VecDeque::new(),
+            pending: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Feeds the next block, returns the transitions with complete context
+    pub fn push(&mut self, bb: Bb) -> Vec<Suspicious> {
+        let (target, last) = match (bb.addr(), bb.stmts.last()) {
+            (Some(x), Some(last)) => (x, last),
+            _ => return Vec::new(),
+        };
+        let i = self.count;
+        self.count += 1;
+        for s in self.pending.iter_mut() {
+            s.context.push(bb.clone());
+        }
+        let tid = bb.tid();
+        let prev = self.prev.insert(tid, (last.addr, last.flow().0));
+        let reason = match prev {
+            Some((_, Flow::Return)) => {
+                let calls = self.calls.entry(tid).or_default();
+                // Returning deeper, e.g. by longjmp, drops the calls above
+                match calls.iter().rposition(|&x| x == target) {
+                    Some(n) => {
+                        calls.truncate(n);
+                        None
+                    }
+                    None if calls.is_empty() => None,
+                    None => Some(Reason::Return),
+                }
+            }
+            Some((_, Flow::IndirectJump)) if !self.blocks.contains_key(&target) => {
+                self.blocks
+                    .range(..target)
+                    .next_back()
+                    .filter(|&(_, &end)| target <= end)
+                    .map(|(&start, _)| Reason::MidBlock(start))
+            }
+            _ => None,
+        };
+        if let (Some(reason), Some((site, _))) = (reason, prev) {
+            let mut context: Vec<Bb> = self.recent.iter().cloned().collect();
+            context.push(bb.clone());
+            self.pending.push(Suspicious {
+                reason,
+                site,
+                target,
+                index: bb.index,
+                block: i,
+                context,
+            });
+        }
+        for s in bb.stmts.iter() {
+            match s.flow().0 {
+                Flow::Call | Flow::IndirectCall => self.calls.entry(tid).or_default().extend(s.next()),
+                _ => (),
+            }
+        }
+        let e = self.blocks.entry(target).or_insert(last.addr);
+        *e = (*e).max(last.addr);
+
+        self.recent.push_back(bb);
+        if self.recent.len() > self.context {
+            self.recent.pop_front();
+        }
+        let context = self.context;
+        let n = self.pending.iter().take_while(|s| s.block + context <= i).count();
+        self.pending.drain(..n).collect()
+    }
+
+    /// Transitions the trace ended before the context of
+    pub fn finish(self) -> Vec<Suspicious> {
+        self.pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rop::*;
+    use trace::TraceStmt;
+    use trace::test;
+
+    fn stmt(addr: usize, hex: &str, text: &str, isbr: bool) -> TraceStmt {
+        TraceStmt { isbr, ..test::stmt(addr, hex, text) }
+    }
+
+    #[test]
+    fn gadgets() {
+        let trace = vec![
+            // Returns of the untraced caller are left alone
+            stmt(0x0fe0, "C3", "ret", true),
+            // ff0: call 0x1000
+            stmt(0x0ff0, "E80B000000", "call 0x1000", true),
+            // 1000: call 0x2000
+            stmt(0x1000, "E8FB0F0000", "call 0x2000", true),
+            // 2000: pop rdi; ret
+            stmt(0x2000, "5F", "pop rdi", false),
+            stmt(0x2001, "C3", "ret", true),
+            // Legitimate return
+            stmt(0x1005, "C3", "ret", true),
+            // Returned to the gadget
+            stmt(0x2001, "C3", "ret", true),
+            // 3000: mov eax, 0x3005; jmp rax
+            stmt(0x3000, "B805300000", "mov eax, 0x3005", false),
+            stmt(0x3005, "FFE0", "jmp rax", true),
+            stmt(0x3005, "FFE0", "jmp rax", true),
+            stmt(0x3000, "90", "nop", true),
+        ];
+        let mut detector = Detector::new(1);
+        let mut res = Vec::new();
+        for bb in Bb::new(trace) {
+            res.extend(detector.push(bb));
+        }
+        res.extend(detector.finish());
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].reason, Reason::Return);
+        assert_eq!((res[0].site, res[0].target, res[0].index), (0x1005, 0x2001, 6));
+        assert_eq!(res[1].reason, Reason::Return);
+        assert_eq!(res[1].target, 0x3000);
+        assert_eq!(res[2].reason, Reason::MidBlock(0x3000));
+        let context: Vec<usize> = res[2].context.iter().map(|x| x.index).collect();
+        assert_eq!((res[2].block, context), (7, vec![7, 9, 10]));
+    }
+}
@@ -0,0 +1,163 @@
+//! Strongly connected components of the traced graph
+//!
+//! Components are found by the Tarjan's algorithm, the condensation is the
+//! acyclic graph of them, which is a sketch of a large graph.
+
+use cfg::{Cfg, Key, NodeBase};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+pub struct Component {
+    pub verts: BTreeSet<Key>,
+    /// Vertices entered from other components, or the first traced one
+    pub entries: BTreeSet<Key>,
+    /// Number of the instrs executed in the component
+    pub instrs: usize,
+}
+
+pub struct Condensation {
+    /// Components in topological order
+    pub comps: Vec<Component>,
+    /// Component of every vertex
+    pub owner: BTreeMap<Key, usize>,
+    /// Number of the transfers between the components
+    pub edges: BTreeMap<(usize, usize), usize>,
+}
+
+impl Condensation {
+    pub fn new(cfg: &Cfg) -> Condensation {
+        let mut sccs = tarjan(cfg);
+        sccs.reverse();
+        let owner: BTreeMap<Key, usize> = sccs
+            .iter()
+            .enumerate()
+            .flat_map(|(i, c)| c.iter().map(move |&k| (k, i)))
+            .collect();
+        let mut comps: Vec<Component> = sccs
+            .into_iter()
+            .map(|c| {
+                let instrs = c.iter()
+                    .map(|k| {
+                        let v = &cfg.verts[k];
+                        match v.node {
+                            NodeBase::Block(ref b) => v.count * b.instrs.len(),
+                            NodeBase::Foreign(_) => 0,
+                        }
+                    })
+                    .sum();
+                Component {
+                    verts: c.into_iter().collect(),
+                    entries: BTreeSet::new(),
+                    instrs,
+                }
+            })
+            .collect();
+
+        let mut edges = BTreeMap::new();
+        for (s, ts) in cfg.edges.iter() {
+            for (t, e) in ts.iter() {
+                let (a, b) = (owner[s], owner[t]);
+                if a != b {
+                    *edges.entry((a, b)).or_insert(0) += e.count;
+                    comps[b].entries.insert(*t);
+                }
+            }
+        }
+        if let Some(k) = cfg.entry() {
+            comps[owner[&k]].entries.insert(k);
+        }
+        Condensation {
+            comps,
+            owner,
+            edges,
+        }
+    }
+}
+
+/// Components in reverse topological order
+fn tarjan(cfg: &Cfg) -> Vec<Vec<Key>> {
+    let mut res = Vec::new();
+    // Visiting order and the least one reachable of every vertex
+    let mut index: HashMap<Key, usize> = HashMap::new();
+    let mut low: HashMap<Key, usize> = HashMap::new();
+    let mut stack = Vec::new();
+    let mut on_stack = HashSet::new();
+    for &root in cfg.verts.keys() {
+        if index.contains_key(&root) {
+            continue;
+        }
+        // Vertices being visited along with their unvisited successors
+        let mut calls: Vec<(Key, Vec<Key>)> = Vec::new();
+        let mut next = Some(root);
+        loop {
+            if let Some(v) = next.take() {
+                let i = index.len();
+                index.insert(v, i);
+                low.insert(v, i);
+                stack.push(v);
+                on_stack.insert(v);
+                calls.push((v, cfg.succs(v).into_iter().rev().collect()));
+            }
+            let (v, w) = match calls.last_mut() {
+                Some(&mut (v, ref mut succs)) => (v, succs.pop()),
+                None => break,
+            };
+            match w {
+                Some(w) if !index.contains_key(&w) => next = Some(w),
+                Some(w) => {
+                    if on_stack.contains(&w) {
+                        let l = low[&v].min(index[&w]);
+                        low.insert(v, l);
+                    }
+                }
+                None => {
+                    calls.pop();
+                    if let Some(&(u, _)) = calls.last() {
+                        let l = low[&u].min(low[&v]);
+                        low.insert(u, l);
+                    }
+                    if low[&v] == index[&v] {
+                        let mut comp = Vec::new();
+                        loop {
+                            let x = stack.pop().unwrap();
+                            on_stack.remove(&x);
+                            comp.push(x);
+                            if x == v {
+                                break;
+                            }
+                        }
+                        res.push(comp);
+                    }
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use scc::*;
+    use trace::Bb;
+    use trace::test::at;
+
+    #[test]
+    fn condensation() {
+        // 1 -> {2 <-> 3, 3 -> 4 -> 2} -> 5 -> 6 -> 5 -> 7
+        let path = vec![1, 2, 3, 2, 3, 4, 2, 3, 5, 6, 5, 7];
+        let cfg = Cfg::from_blocks(Bb::new(path.into_iter().map(at)));
+        let cond = Condensation::new(&cfg);
+        let k = Key::from;
+        let keys = |v: Vec<usize>| v.into_iter().map(k).collect::<BTreeSet<_>>();
+
+        let comps: Vec<BTreeSet<Key>> = cond.comps.iter().map(|c| c.verts.clone()).collect();
+        assert_eq!(comps, vec![keys(vec![1]), keys(vec![2, 3, 4]), keys(vec![5, 6]), keys(vec![7])]);
+        assert_eq!(cond.comps[0].entries, keys(vec![1]));
+        assert_eq!(cond.comps[1].entries, keys(vec![2]));
+        assert_eq!(cond.comps[1].instrs, 7);
+        assert_eq!(cond.owner[&k(4)], 1);
+        let edges: Vec<(usize, usize)> = cond.edges.keys().cloned().collect();
+        assert_eq!(edges, vec![(0, 1), (1, 2), (2, 3)]);
+        assert_eq!(cond.edges[&(1, 2)], 1);
+    }
+}
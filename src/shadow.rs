@@ -0,0 +1,411 @@
+//! Shadow call stack along the trace
+//!
+//! Frames are pushed by calls and popped by returns of every thread. Each
+//! transfer between two blocks is resolved once the target block is known,
+//! the unusual ones are reported as events. Calls into the foreign code are
+//! expected to return right away, except for the non-local jumps and the
+//! exception throwing recognized by the name of the callee.
+
+use base::Addressable;
+use disasm::Flow;
+use func;
+use trace::Bb;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+/// Foreign functions jumping to the `setjmp` return site
+const LONGJMP: &[&str] = &["longjmp", "_longjmp", "siglongjmp", "__longjmp_chk"];
+
+/// Foreign functions unwinding to the landing pad of the catching function
+const THROW: &[&str] = &[
+    "__cxa_throw",
+    "__cxa_rethrow",
+    "_Unwind_RaiseException",
+    "_Unwind_Resume",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Call { site: usize, entry: usize },
+    /// Transfer into the foreign code, `tail` if by a jump
+    Foreign { site: usize, name: String, tail: bool },
+    Return { site: usize, to: usize },
+    /// Return to an address other than the pushed one, `frames` are popped
+    /// if it is the return address of an outer frame
+    Mismatch {
+        site: usize,
+        to: usize,
+        expected: Option<usize>,
+        frames: usize,
+    },
+    /// Jump to another function entry replacing the current frame
+    TailCall { site: usize, entry: usize },
+    /// Non-local jump to a return site of an outer frame
+    Longjmp { site: usize, to: usize, frames: usize },
+    /// Unwinding to the landing pad of an outer frame
+    Exception { site: usize, to: usize, frames: usize },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Call { site, entry } => write!(f, "call {:x} -> {}", site, func::name(entry)),
+            Event::Foreign { site, ref name, tail } => {
+                let kind = if tail { "foreign tail call" } else { "foreign call" };
+                write!(f, "{} {:x} -> {}", kind, site, name)
+            }
+            Event::Return { site, to } => write!(f, "return {:x} -> {:x}", site, to),
+            Event::Mismatch { site, to, expected, frames } => {
+                write!(f, "mismatched return {:x} -> {:x}", site, to)?;
+                if let Some(x) = expected {
+                    write!(f, ", expected {:x}", x)?;
+                }
+                write!(f, ", {} frames popped", frames)
+            }
+            Event::TailCall { site, entry } => write!(f, "tail call {:x} -> {}", site, func::name(entry)),
+            Event::Longjmp { site, to, frames } => {
+                write!(f, "longjmp {:x} -> {:x}, {} frames popped", site, to, frames)
+            }
+            Event::Exception { site, to, frames } => {
+                write!(f, "exception {:x} -> {:x}, {} frames popped", site, to, frames)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Entry of the function being executed
+    pub entry: usize,
+    /// Return address pushed by the call, `None` for the outermost frame
+    pub ret: Option<usize>,
+    /// Return sites of the calls made from the frame
+    sites: BTreeSet<usize>,
+}
+
+impl Frame {
+    fn new(entry: usize, ret: Option<usize>) -> Frame {
+        Frame {
+            entry,
+            ret,
+            sites: BTreeSet::new(),
+        }
+    }
+}
+
+/// Terminating instr of the block, resolved with the next block
+struct Exit {
+    site: usize,
+    flow: Flow,
+    /// Address of the following instr, the return address of a call
+    next: Option<usize>,
+    foreign: Option<String>,
+}
+
+#[derive(Default)]
+struct Thread {
+    stack: Vec<Frame>,
+    exit: Option<Exit>,
+}
+
+pub struct Shadow {
+    threads: HashMap<Option<usize>, Thread>,
+    /// Targets of the calls seen so far
+    entries: HashSet<usize>,
+}
+
+impl Thread {
+    /// Pops the frames above the `depth`
+    fn unwind(&mut self, depth: usize) -> usize {
+        let n = self.stack.len() - depth;
+        self.stack.truncate(depth);
+        n
+    }
+
+    /// Depth of the frame returned to by `to`, the top one excluded
+    fn returned(&self, to: usize) -> Option<usize> {
+        let n = self.stack.len() - 1;
+        self.stack[..n].iter().rposition(|x| x.ret == Some(to)).map(|x| x + 1)
+    }
+
+    fn resolve(&mut self, e: Exit, to: usize, entries: &mut HashSet<usize>) -> Vec<Event> {
+        let site = e.site;
+        if let Some(name) = e.foreign {
+            if LONGJMP.contains(&name.as_str()) {
+                // Return site of `setjmp`
+                return match self.stack.iter().rposition(|x| x.sites.contains(&to)) {
+                    Some(i) => vec![Event::Longjmp {
+                        site,
+                        to,
+                        frames: self.unwind(i + 1),
+                    }],
+                    None => Vec::new(),
+                };
+            }
+            if THROW.contains(&name.as_str()) {
+                // Landing pad follows the call which has thrown
+                let nearest = self.stack.iter().enumerate().filter_map(|(i, x)| {
+                    x.sites.range(..=to).next_back().map(|&r| (r, i))
+                });
+                return match nearest.max() {
+                    Some((_, i)) => vec![Event::Exception {
+                        site,
+                        to,
+                        frames: self.unwind(i + 1),
+                    }],
+                    None => Vec::new(),
+                };
+            }
+            let tail = !matches!(e.flow, Flow::Call | Flow::IndirectCall);
+            let mut res = vec![Event::Foreign {
+                site,
+                name,
+                tail,
+            }];
+            match (tail, e.next) {
+                (true, _) => res.extend(self.ret(site, to)),
+                (false, Some(ret)) => {
+                    self.stack.last_mut().unwrap().sites.insert(ret);
+                }
+                _ => (),
+            }
+            return res;
+        }
+        match e.flow {
+            // `call $+5` only gets the pc
+            Flow::Call | Flow::IndirectCall if e.next != Some(to) => {
+                entries.insert(to);
+                if let Some(ret) = e.next {
+                    self.stack.last_mut().unwrap().sites.insert(ret);
+                }
+                self.stack.push(Frame::new(to, e.next));
+                vec![Event::Call {
+                    site,
+                    entry: to,
+                }]
+            }
+            Flow::Return => self.ret(site, to),
+            Flow::Jump | Flow::IndirectJump if entries.contains(&to) => {
+                let top = self.stack.last_mut().unwrap();
+                if top.entry == to {
+                    return Vec::new();
+                }
+                top.entry = to;
+                vec![Event::TailCall {
+                    site,
+                    entry: to,
+                }]
+            }
+            Flow::IndirectJump => {
+                let n = self.stack.len() - 1;
+                match self.stack[..n].iter().rposition(|x| x.sites.contains(&to)) {
+                    Some(i) => vec![Event::Longjmp {
+                        site,
+                        to,
+                        frames: self.unwind(i + 1),
+                    }],
+                    None => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn ret(&mut self, site: usize, to: usize) -> Vec<Event> {
+        let depth = self.stack.len();
+        let expected = self.stack[depth - 1].ret;
+        // Return address is unknown without the hexdump of the call
+        let unknown = depth > 1 && expected.is_none();
+        if expected == Some(to) || unknown {
+            self.stack.pop();
+        } else if depth == 1 {
+            // Returned from the function the trace was started in
+            self.stack[0] = Frame::new(to, None);
+        } else {
+            let frames = match self.returned(to) {
+                Some(d) => self.unwind(d - 1),
+                None => 0,
+            };
+            return vec![Event::Mismatch {
+                site,
+                to,
+                expected,
+                frames,
+            }];
+        }
+        vec![Event::Return {
+            site,
+            to,
+        }]
+    }
+}
+
+impl Shadow {
+    pub fn new() -> Shadow {
+        Shadow {
+            threads: HashMap::new(),
+            entries: HashSet::new(),
+        }
+    }
+
+    /// Feeds the next block, returns the events of the transfer into it
+    pub fn push(&mut self, bb: &Bb) -> Vec<Event> {
+        let addr = match bb.addr() {
+            Some(addr) => addr,
+            None => return Vec::new(),
+        };
+        let t = self.threads.entry(bb.tid()).or_default();
+        let events = match t.exit.take() {
+            Some(e) => t.resolve(e, addr, &mut self.entries),
+            None => {
+                t.stack.push(Frame::new(addr, None));
+                Vec::new()
+            }
+        };
+        t.exit = bb.last_instr().map(|x| {
+            Exit {
+                site: x.addr,
+                flow: x.flow().0,
+                next: x.next(),
+                foreign: bb.foreign_info().map(|f| f.foreign_name),
+            }
+        });
+        events
+    }
+
+    /// Active frames of the thread, the outermost first
+    pub fn stack(&self, tid: Option<usize>) -> &[Frame] {
+        self.threads.get(&tid).map_or(&[], |x| &x.stack)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shadow::*;
+    use base::ForeignInfo;
+    use trace::TraceStmt;
+    use trace::test::stmt;
+
+    fn foreign(mut s: TraceStmt, name: &str) -> TraceStmt {
+        s.foreign = Some(ForeignInfo {
+            foreign_addr: 0x7f0000000000,
+            foreign_name: name.to_string(),
+            stub: false,
+        });
+        s
+    }
+
+    /// Stack and events of the block
+    struct Step {
+        stack: Vec<usize>,
+        events: Vec<Event>,
+    }
+
+    fn run(trace: Vec<TraceStmt>) -> Vec<Step> {
+        let mut shadow = Shadow::new();
+        Bb::new(trace)
+            .map(|x| {
+                let events = shadow.push(&x);
+                Step {
+                    stack: shadow.stack(x.tid()).iter().map(|x| x.entry).collect(),
+                    events,
+                }
+            })
+            .collect()
+    }
+
+    // call 0x2000 from 0x1000, 0x1005 and 0x100a
+    const CALL: &str = "E8FB0F0000";
+    const CALL2: &str = "E8F60F0000";
+    const CALL3: &str = "E8F10F0000";
+
+    #[test]
+    fn calls() {
+        let steps = run(vec![
+            stmt(0x1000, CALL, "call 0x2000"),
+            stmt(0x2000, "C3", "ret"),
+            stmt(0x1005, "C3", "ret"),
+            stmt(0x3000, "90", "nop"),
+        ]);
+        assert_eq!(steps[1].stack, vec![0x1000, 0x2000]);
+        assert_eq!(steps[1].events, vec![Event::Call { site: 0x1000, entry: 0x2000 }]);
+        assert_eq!(steps[2].stack, vec![0x1000]);
+        assert_eq!(steps[2].events, vec![Event::Return { site: 0x2000, to: 0x1005 }]);
+        // Returned out of the first function
+        assert_eq!(steps[3].stack, vec![0x3000]);
+    }
+
+    #[test]
+    fn mismatch() {
+        let steps = run(vec![
+            stmt(0x1000, CALL, "call 0x2000"),
+            stmt(0x2000, CALL, "call 0x2000"),
+            stmt(0x2000, "C3", "ret"),
+            stmt(0x4000, "90", "nop"),
+        ]);
+        let mismatch = Event::Mismatch {
+            site: 0x2000,
+            to: 0x4000,
+            expected: Some(0x2005),
+            frames: 0,
+        };
+        assert_eq!(steps[3].events, vec![mismatch]);
+        assert_eq!(steps[3].stack, vec![0x1000, 0x2000, 0x2000]);
+    }
+
+    #[test]
+    fn tail_call() {
+        let steps = run(vec![
+            stmt(0x1000, CALL, "call 0x2000"),
+            stmt(0x2000, "C3", "ret"),
+            stmt(0x1005, "E9F60F0000", "jmp 0x2000"),
+            stmt(0x2000, "C3", "ret"),
+        ]);
+        assert_eq!(steps[3].events, vec![Event::TailCall { site: 0x1005, entry: 0x2000 }]);
+        assert_eq!(steps[3].stack, vec![0x2000]);
+    }
+
+    #[test]
+    fn longjmp() {
+        // setjmp at 0x1000, then calls nested two levels deep longjmp back
+        let steps = run(vec![
+            foreign(stmt(0x1000, "E800000000", "call 0x1005"), "setjmp"),
+            stmt(0x1005, CALL2, "call 0x2000"),
+            stmt(0x2000, CALL, "call 0x3000"),
+            foreign(stmt(0x3000, "E800000000", "call 0x3005"), "longjmp"),
+            stmt(0x1005, "90", "nop"),
+        ]);
+        assert_eq!(steps[0].events, vec![]);
+        assert_eq!(steps[1].events[0], Event::Foreign { site: 0x1000, name: "setjmp".to_string(), tail: false });
+        assert_eq!(steps[3].stack, vec![0x1000, 0x2000, 0x3000]);
+        assert_eq!(steps[4].events, vec![Event::Longjmp { site: 0x3000, to: 0x1005, frames: 2 }]);
+        assert_eq!(steps[4].stack, vec![0x1000]);
+    }
+
+    #[test]
+    fn exception() {
+        let steps = run(vec![
+            stmt(0x100a, CALL3, "call 0x2000"),
+            foreign(stmt(0x2000, "E800000000", "call 0x2005"), "__cxa_throw"),
+            stmt(0x1040, "90", "nop"),
+        ]);
+        assert_eq!(steps[2].events, vec![Event::Exception { site: 0x2000, to: 0x1040, frames: 1 }]);
+        assert_eq!(steps[2].stack, vec![0x100a]);
+    }
+
+    #[test]
+    fn foreign_tail_call() {
+        // call to the plt stub jumping to puts
+        let steps = run(vec![
+            stmt(0x1000, CALL, "call 0x2000"),
+            foreign(stmt(0x2000, "FF25FA2F0000", "jmp qword ptr [rip+0x2ffa]"), "puts"),
+            stmt(0x1005, "C3", "ret"),
+        ]);
+        assert_eq!(steps[2].events, vec![
+            Event::Foreign { site: 0x2000, name: "puts".to_string(), tail: true },
+            Event::Return { site: 0x2000, to: 0x1005 },
+        ]);
+        assert_eq!(steps[2].stack, vec![0x1000]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Dynamic backward slicing of the trace
+//!
+//! The statements which wrote the values a location is computed from are
+//! followed back along the executed path, the registers within the thread and
+//! the memory across the threads. Memory is followed only where the trace
+//! records the accessed addresses, the registers addressing it always are.
+
+use disasm::{Reg, DEFAULT_BITNESS};
+use trace::TraceStmt;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Reg(Reg),
+    /// Address and size of the memory
+    Mem(usize, usize),
+}
+
+impl Location {
+    /// Register name, or `[<address>]` and `[<address>:<size>]` for the memory
+    ///
+    /// The address is hex, the size is a machine word unless given.
+    pub fn parse(s: &str) -> Option<Location> {
+        if s.starts_with('[') && s.ends_with(']') {
+            let mut mem = s[1..s.len() - 1].splitn(2, ':');
+            let addr = usize::from_str_radix(mem.next()?.trim_start_matches("0x"), 16).ok()?;
+            let size = match mem.next() {
+                Some(x) => x.parse().ok()?,
+                None => DEFAULT_BITNESS as usize / 8,
+            };
+            return Some(Location::Mem(addr, size));
+        }
+        Reg::parse(s, DEFAULT_BITNESS).map(Location::Reg)
+    }
+}
+
+/// Disjoint ranges of the memory, the end by the start
+#[derive(Debug, Default)]
+struct Ranges(BTreeMap<usize, usize>);
+
+impl Ranges {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn insert(&mut self, mut start: usize, mut end: usize) {
+        let joined: Vec<(usize, usize)> = self.0
+            .range(..=end)
+            .rev()
+            .take_while(|&(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in joined {
+            self.0.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.0.insert(start, end);
+    }
+
+    /// Cuts the range out, returns whether any of it was there
+    fn remove(&mut self, start: usize, end: usize) -> bool {
+        let cut: Vec<(usize, usize)> = self.0
+            .range(..end)
+            .rev()
+            .take_while(|&(_, &e)| e > start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for &(s, e) in cut.iter() {
+            self.0.remove(&s);
+            if s < start {
+                self.0.insert(s, start);
+            }
+            if e > end {
+                self.0.insert(end, e);
+            }
+        }
+        !cut.is_empty()
+    }
+}
+
+/// Trace indices of the statements contributing to the value `loc` has just
+/// before the statement `index` is executed
+pub fn slice(stmts: &[TraceStmt], index: usize, loc: Location) -> BTreeSet<usize> {
+    let mut res = BTreeSet::new();
+    let tid = match stmts.get(index) {
+        Some(s) => s.tid,
+        None => return res,
+    };
+    // Locations still waiting for the statement writing them
+    let mut regs: HashMap<Option<usize>, BTreeSet<Reg>> = HashMap::new();
+    let mut mem = Ranges::default();
+    match loc {
+        Location::Reg(r) => {
+            regs.entry(tid).or_default().insert(r);
+        }
+        Location::Mem(addr, size) => mem.insert(addr, addr.saturating_add(size)),
+    }
+
+    for i in (0..index).rev() {
+        if mem.is_empty() && regs.values().all(|x| x.is_empty()) {
+            break;
+        }
+        let s = &stmts[i];
+        let a = s.decode().map(|d| d.access.clone()).unwrap_or_default();
+        let wanted = regs.entry(s.tid).or_default();
+        let mut written = false;
+        for m in s.memory.iter().filter(|m| m.write) {
+            written |= mem.remove(m.addr, m.addr.saturating_add(m.size));
+        }
+        if !written && a.writes.is_disjoint(wanted) {
+            continue;
+        }
+        res.insert(i);
+        for r in a.kills.iter() {
+            wanted.remove(r);
+        }
+        wanted.extend(a.reads.iter().cloned());
+        for m in s.memory.iter().filter(|m| !m.write) {
+            mem.insert(m.addr, m.addr.saturating_add(m.size));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use slice::*;
+    use trace::{self, MemAccess};
+
+    fn stmt(addr: usize, hex: &str, memory: Vec<MemAccess>) -> TraceStmt {
+        TraceStmt {
+            memory,
+            ..trace::test::stmt(addr, hex, "")
+        }
+    }
+
+    fn trace(memory: bool) -> Vec<TraceStmt> {
+        let access = |write| if memory {
+            vec![MemAccess {
+                addr: 0x1000,
+                size: 4,
+                write,
+            }]
+        } else {
+            Vec::new()
+        };
+        vec![
+            // mov eax, 1; mov ebx, 2; mov dword ptr [rsp], eax
+            stmt(0, "B801000000", Vec::new()),
+            stmt(5, "BB02000000", Vec::new()),
+            stmt(0xa, "890424", access(true)),
+            // mov ebx, 3; mov ecx, dword ptr [rsp]; add ecx, ebx; nop
+            stmt(0xd, "BB03000000", Vec::new()),
+            stmt(0x12, "8B0C24", access(false)),
+            stmt(0x15, "01D9", Vec::new()),
+            stmt(0x17, "90", Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn slicing() {
+        let rcx = Location::parse("ecx").unwrap();
+        let indices = |s: BTreeSet<usize>| s.into_iter().collect::<Vec<_>>();
+        assert_eq!(indices(slice(&trace(true), 6, rcx)), vec![0, 2, 3, 4, 5]);
+        // The store is not known without the addresses
+        assert_eq!(indices(slice(&trace(false), 6, rcx)), vec![3, 4, 5]);
+        assert_eq!(indices(slice(&trace(true), 4, Location::parse("[1000:4]").unwrap())), vec![0, 2]);
+        assert_eq!(Location::parse("[0x1000]"), Some(Location::Mem(0x1000, 8)));
+        assert_eq!(Location::parse("[zz]"), None);
+    }
+
+    #[test]
+    fn ranges() {
+        let mut r = Ranges::default();
+        r.insert(0x10, 0x18);
+        r.insert(0x20, 0x28);
+        r.insert(0x18, 0x20);
+        assert_eq!(r.0.iter().collect::<Vec<_>>(), vec![(&0x10, &0x28)]);
+        assert!(!r.remove(0, 0x10));
+        assert!(r.remove(0x14, 0x24));
+        assert_eq!(r.0.iter().collect::<Vec<_>>(), vec![(&0x10, &0x14), (&0x24, &0x28)]);
+        assert!(r.remove(0, usize::MAX));
+        assert!(r.is_empty());
+    }
+}
@@ -1,4 +1,7 @@
-use base::{Addressable, Block, Instr, ForeignInfo};
+use base::{Addressable, Block, Instr, ForeignInfo, Lazy};
+use disasm::{self, Decoded, Flow};
+use version::Versions;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct TraceStmt {
@@ -12,56 +15,168 @@ pub struct TraceStmt {
     pub isbr: bool,
     /// Information about the foreign branch
     pub foreign: Option<ForeignInfo>,
+    /// Thread the instr is executed in
+    pub tid: Option<usize>,
+    /// Memory accessed by the instr, if the tracer records it
+    pub memory: Vec<MemAccess>,
+    /// Decoded hexdump, reset it along with a change of the hexdump
+    pub decoded: Lazy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemAccess {
+    pub addr: usize,
+    /// Size in bytes
+    pub size: usize,
+    pub write: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Bb {
     pub stmts: Vec<TraceStmt>,
+    /// Trace index of the first statement
+    pub index: usize,
+    /// Trace index of the last statement, threads may be interleaved
+    pub last: usize,
+    /// Generation of the code of the statements, see `Versions`
+    pub gen: usize,
 }
 
-impl Bb {
-    pub fn new(stmts: Vec<TraceStmt>) -> Vec<Bb> {
-        let mut tmp = stmts.into_iter().fold(vec![Vec::new()], |mut vvs, t| {
-            let last = vvs.len() - 1;
-            let b = t.isbr;
-            vvs[last].push(t);
+/// Lazily splits a stream of statements into basic blocks
+///
+/// Interleaved statements of different threads are split independently,
+/// a block is yielded as soon as its thread reaches a branch. A block is
+/// also ended where the generation of the code changes, so that all its
+/// statements are of the same one.
+/// Statements after the last branch do not form a block and are dropped.
+pub struct Blocks<I> {
+    stmts: I,
+    /// Incomplete block of every thread
+    pending: HashMap<Option<usize>, Bb>,
+    /// Trace index of the next statement
+    index: usize,
+    /// Versions of the code executed so far
+    versions: Versions,
+    /// Block completed along with the one ended by the generation change
+    ready: Option<Bb>,
+}
+
+impl<I: Iterator<Item = TraceStmt>> Iterator for Blocks<I> {
+    type Item = Bb;
+
+    fn next(&mut self) -> Option<Bb> {
+        if let Some(bb) = self.ready.take() {
+            return Some(bb);
+        }
+        for t in self.stmts.by_ref() {
+            let (b, tid, index) = (t.isbr, t.tid, self.index);
+            self.index += 1;
+            let gen = self.versions.push(index, &t);
+            // The changes are reported by the `Versions` run on its own
+            self.versions.records.clear();
+            let ended = match self.pending.get(&tid) {
+                Some(bb) if bb.gen != gen => self.pending.remove(&tid),
+                _ => None,
+            };
+            let bb = self.pending.entry(tid).or_insert_with(|| {
+                Bb {
+                    stmts: Vec::new(),
+                    index,
+                    last: index,
+                    gen,
+                }
+            });
+            bb.last = index;
+            bb.stmts.push(t);
             if b {
-                vvs.push(Vec::new());
+                let bb = self.pending.remove(&tid);
+                if ended.is_none() {
+                    return bb;
+                }
+                self.ready = bb;
+            }
+            if ended.is_some() {
+                return ended;
             }
-            vvs
-        });
-        let _ = tmp.pop();
+        }
+        None
+    }
+}
+
+impl TraceStmt {
+    pub fn into_instr(self) -> Instr {
+        Instr {
+            decoded: self.decoded,
+            ..Instr::new(self.addr, self.hex, self.text, self.isbr)
+        }
+    }
+
+    /// Decodes the hexdump without building the instr, only the first time
+    pub fn decode(&self) -> Option<&Decoded> {
+        self.decoded.get(&self.hex, self.addr)
+    }
+
+    /// Control-flow class and the direct target, as of `Instr::flow`
+    pub fn flow(&self) -> (Flow, Option<usize>) {
+        match self.decode() {
+            Some(d) => (d.flow, d.target),
+            None => disasm::parse_flow(&self.text),
+        }
+    }
+
+    /// Address of the following instr, as of `Instr::next`
+    pub fn next(&self) -> Option<usize> {
+        if self.hex.is_empty() {
+            None
+        } else {
+            Some(self.addr + self.hex.len() / 2)
+        }
+    }
+}
 
-        tmp.into_iter().map(|x| Bb { stmts: x }).collect()
+impl Bb {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<I: IntoIterator<Item = TraceStmt>>(stmts: I) -> Blocks<I::IntoIter> {
+        Blocks {
+            stmts: stmts.into_iter(),
+            pending: HashMap::new(),
+            index: 0,
+            versions: Versions::new(),
+            ready: None,
+        }
     }
 
     pub fn separate(self) -> (Block, Option<ForeignInfo>) {
         let f = self.foreign_info();
-        let i = self.stmts.into_iter().map(|x| {
-            Instr {
-                addr: x.addr,
-                hex: x.hex,
-                text: x.text,
-                isbr: x.isbr,
-            }
-        });
+        let i = self.stmts.into_iter().map(|x| x.into_instr());
         (Block { instrs: i.collect() }, f)
     }
 
     pub fn foreign_info(&self) -> Option<ForeignInfo> {
         self.stmts.last()?.foreign.clone()
     }
+
+    /// Terminating instr of the block
+    pub fn last_instr(&self) -> Option<Instr> {
+        self.stmts.last().map(|x| x.clone().into_instr())
+    }
+
+    pub fn tid(&self) -> Option<usize> {
+        self.stmts.first()?.tid
+    }
 }
 
 impl Addressable for Bb {
     fn addr(&self) -> Option<usize> {
-        self.stmts.iter().nth(0).map(|x| x.addr)
+        self.stmts.first().map(|x| x.addr)
     }
 }
 
 #[cfg(test)]
 pub mod test {
     use trace::{TraceStmt, Bb, Addressable};
+    use base::Lazy;
+    use disasm::Flow;
     use parsing::test::traces;
 
     #[macro_export]
@@ -73,21 +188,88 @@ pub mod test {
                     text: String::new(),
                     isbr: false,
                     foreign: None,
+                    tid: None,
+                    memory: Vec::new(),
+                    decoded: Default::default(),
                 }
             )
     }
 
+    /// Statement of a branch, the fixture of the tests
+    pub fn stmt(addr: usize, hex: &str, text: &str) -> TraceStmt {
+        TraceStmt {
+            addr,
+            hex: hex.to_string(),
+            text: text.to_string(),
+            isbr: true,
+            foreign: None,
+            tid: None,
+            memory: Vec::new(),
+            decoded: Lazy::default(),
+        }
+    }
+
+    /// Branch known only by the address
+    pub fn at(addr: usize) -> TraceStmt {
+        stmt(addr, "", "")
+    }
+
+    #[test]
+    fn memoized_decode() {
+        let mut s = stmt(0x1000, "7410", "");
+        assert_eq!(s.flow(), (Flow::Conditional, Some(0x1012)));
+        // Decoded once, the stale hexdump is still in use
+        s.hex = "C3".to_string();
+        assert_eq!(s.flow().0, Flow::Conditional);
+        assert_eq!(s.into_instr().flow().0, Flow::Conditional);
+    }
+
     #[test]
     fn from_traces() {
-        let bbs = Bb::new(traces());
+        let bbs: Vec<Bb> = Bb::new(traces()).collect();
         assert_eq!(bbs.len(), 2);
         assert_eq!(bbs[0].stmts.len(), 3);
         assert_eq!(bbs[1].stmts.len(), 4);
+        assert_eq!(bbs[1].index, 3);
+    }
+
+    #[test]
+    fn threads() {
+        let mut stmts = Vec::new();
+        for i in 0..6 {
+            // Two threads taking turns
+            let mut t = new_trace!(i);
+            t.tid = Some(i % 2);
+            t.isbr = i >= 4;
+            stmts.push(t);
+        }
+        let bbs: Vec<Bb> = Bb::new(stmts).collect();
+        assert_eq!(bbs.len(), 2);
+        assert_eq!(bbs[0].tid(), Some(0));
+        assert_eq!((bbs[0].index, bbs[1].index), (0, 1));
+        assert_eq!(bbs[0].stmts.iter().map(|x| x.addr).collect::<Vec<_>>(), vec![0, 2, 4]);
+        assert_eq!(bbs[1].tid(), Some(1));
+        assert_eq!(bbs[1].stmts.iter().map(|x| x.addr).collect::<Vec<_>>(), vec![1, 3, 5]);
     }
 
+    #[test]
+    fn generations() {
+        // nop; ret, then the nop rewritten to int3
+        let stmt = |addr, hex: &str| {
+            let mut t = new_trace!(addr);
+            t.hex = hex.to_string();
+            t.isbr = hex == "C3";
+            t
+        };
+        let trace = vec![stmt(0x1000, "90"), stmt(0x1001, "C3"), stmt(0x1000, "CC"), stmt(0x1001, "C3")];
+        let bbs: Vec<(usize, usize, usize)> = Bb::new(trace)
+            .map(|x| (x.addr().unwrap(), x.stmts.len(), x.gen))
+            .collect();
+        assert_eq!(bbs, vec![(0x1000, 2, 0), (0x1000, 1, 1), (0x1001, 1, 0)]);
+    }
 
     #[test]
     fn addr() {
-        assert_eq!(Bb { stmts: vec![new_trace!(11)] }.addr().unwrap(), 11);
+        assert_eq!(Bb { stmts: vec![new_trace!(11)], index: 0, last: 0, gen: 0 }.addr().unwrap(), 11);
     }
 }
@@ -0,0 +1,150 @@
+//! Versioning of the code rewritten during the execution
+//!
+//! Packed and JIT-generated code executes different instrs at the same
+//! address. Every change of the hexdump executed at an address starts a new
+//! generation of the code there. Only the current bytes of every address are
+//! kept, so the code restored to the earlier bytes gets a new generation too.
+
+use trace::TraceStmt;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Instr differs from the one executed at the address before
+    Rewrite { old: String, new: String, gen: usize },
+    /// Instr overlaps the one executed before at the other address
+    Overlap { other: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub addr: usize,
+    /// Trace index of the first execution of the changed code
+    pub index: usize,
+    pub change: Change,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{:x}\t", self.index, self.addr)?;
+        match self.change {
+            Change::Rewrite { ref old, ref new, gen } => {
+                write!(f, "rewritten {} -> {}, generation {}", old, new, gen)
+            }
+            Change::Overlap { other } => write!(f, "overlaps {:x}", other),
+        }
+    }
+}
+
+pub struct Versions {
+    /// Generation and hexdump of the instr last executed at every address
+    current: BTreeMap<usize, (usize, String)>,
+    /// Changes in the order of the trace
+    pub records: Vec<Record>,
+}
+
+impl Versions {
+    pub fn new() -> Versions {
+        Versions {
+            current: BTreeMap::new(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Accounts the statement at the trace `index`, returns its generation
+    ///
+    /// Statements without hexdump can't be told apart, they are always 0.
+    pub fn push(&mut self, index: usize, stmt: &TraceStmt) -> usize {
+        if stmt.hex.is_empty() {
+            return 0;
+        }
+        let addr = stmt.addr;
+        let gen = match self.current.get(&addr) {
+            Some(&(gen, ref hex)) if *hex == stmt.hex => return gen,
+            Some(&(gen, ref hex)) => {
+                self.records.push(Record {
+                    addr,
+                    index,
+                    change: Change::Rewrite {
+                        old: hex.clone(),
+                        new: stmt.hex.clone(),
+                        gen: gen + 1,
+                    },
+                });
+                gen + 1
+            }
+            None => 0,
+        };
+        self.current.insert(addr, (gen, stmt.hex.clone()));
+        let len = stmt.hex.len() / 2;
+        // New code at the address, may be decoded from the middle of another
+        let before = self.current
+            .range(..addr)
+            .next_back()
+            .filter(|&(&x, (_, hex))| x + hex.len() / 2 > addr);
+        let after = self.current.range(addr + 1..addr + len).next();
+        for (&other, _) in before.into_iter().chain(after) {
+            self.records.push(Record {
+                addr,
+                index,
+                change: Change::Overlap { other },
+            });
+        }
+        gen
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use version::*;
+    use trace::test;
+
+    fn stmt(addr: usize, hex: &str) -> TraceStmt {
+        test::stmt(addr, hex, "")
+    }
+
+    #[test]
+    fn generations() {
+        let mut v = Versions::new();
+        // mov eax, 0x90c3; then the immediate rewritten and restored
+        let trace = [stmt(0x1000, "B8C3900000"), stmt(0x1000, "B890900000"), stmt(0x1000, "B8C3900000")];
+        let gens: Vec<usize> = trace.iter().enumerate().map(|(i, s)| v.push(i, s)).collect();
+        assert_eq!(gens, vec![0, 1, 2]);
+        assert_eq!(v.push(3, &stmt(0x1000, "")), 0);
+        assert_eq!(v.records.len(), 2);
+        assert_eq!(
+            v.records[0],
+            Record {
+                addr: 0x1000,
+                index: 1,
+                change: Change::Rewrite {
+                    old: "B8C3900000".to_string(),
+                    new: "B890900000".to_string(),
+                    gen: 1,
+                },
+            }
+        );
+        assert_eq!(v.records[1].index, 2);
+    }
+
+    #[test]
+    fn overlaps() {
+        let mut v = Versions::new();
+        v.push(0, &stmt(0x1000, "B8C3900000"));
+        // ret in the immediate of the mov
+        v.push(1, &stmt(0x1001, "C3"));
+        v.push(2, &stmt(0x1001, "C3"));
+        // Runs into the mov
+        v.push(3, &stmt(0xfff, "66B8"));
+        let res: Vec<(usize, Change)> = v.records.into_iter().map(|x| (x.addr, x.change)).collect();
+        assert_eq!(
+            res,
+            vec![
+                (0x1001, Change::Overlap { other: 0x1000 }),
+                (0xfff, Change::Overlap { other: 0x1000 }),
+            ]
+        );
+    }
+}